@@ -1,14 +1,24 @@
 use crate::{
-    API_MIRROR, BOTTLES_MIRROR, CACHE_DIR, CLIENT_WITH_RETRY, PAC_PATH,
-    database::local::{PacState, SqlTransaction},
+    API_MIRROR, BOTTLES_MIRROR, CACHE_DIR, CLIENT_WITH_RETRY,
+    config::pac_root,
+    database::local::{PacRecord, PacState, SqlTransaction},
     errors::{CatError, CloudError, RequestError},
+    interrupt,
     macos::{
-        file::{CmpPath, remove_dir_force, remove_dir_recursively_force, remove_file_force},
-        version::ARCH_OS,
+        file::{
+            CmpPath, check_existing, remove_dir_force, remove_dir_recursively_force,
+            remove_file_force, unpublish_links,
+        },
+        version::{MACOS_VERSION, MacOSVersion, bottle_tag},
     },
     package::{
-        find_depend::{detect_conflicts, resolve_depend},
-        install::install,
+        audit::{audit_dir, print_leftovers},
+        find_depend::{DependPolicy, detect_conflicts, migrate_renamed, resolve_depend},
+        install::{InstalledFiles, scratch_dir, stage},
+        journal::{self, Action, Journal},
+        linkage::cross_linkage,
+        load_path::verify_load_paths,
+        plan::{InstallPlan, PlanEntry},
         preprocess::before_install,
     },
     scopeguard::DropGuard,
@@ -21,12 +31,12 @@ use sha2::{Digest, Sha256};
 use std::{
     collections::{BTreeSet, HashMap},
     fs,
-    io::{BufReader, Read, Write},
+    io::{self, BufReader, Read, Write},
     iter::zip,
     path::{Path, PathBuf},
-    rc::Rc,
     sync::LazyLock,
 };
+use strum::IntoEnumIterator;
 use tokio::io::AsyncWriteExt;
 
 static PROGRESS_STYLE: LazyLock<ProgressStyle> = LazyLock::new(|| {
@@ -49,6 +59,98 @@ pub struct PacInfo {
     /// we don't support install multi versions for now
     pub versioned_formulae: Vec<String>,
     pub revision: u32,
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    #[serde(default)]
+    pub oldnames: Vec<String>,
+    #[serde(default)]
+    pub deprecated: bool,
+    pub deprecation_date: Option<String>,
+    pub deprecation_reason: Option<String>,
+    #[serde(default)]
+    pub disabled: bool,
+    pub disable_date: Option<String>,
+    pub disable_reason: Option<String>,
+    pub caveats: Option<String>,
+    #[serde(default)]
+    pub uses_from_macos: Vec<UsesFromMacos>,
+    /// one bound for each entry of `uses_from_macos`
+    #[serde(default)]
+    pub uses_from_macos_bounds: Vec<MacosBound>,
+    /// keg-only formulae are not linked into the prefix
+    #[serde(default)]
+    pub keg_only: bool,
+    pub keg_only_reason: Option<KegOnlyReason>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct KegOnlyReason {
+    /// a symbol such as `:provided_by_macos` or `:versioned_formula`
+    pub reason: String,
+    #[serde(default)]
+    pub explanation: String,
+}
+
+/// `"zlib"` for runtime dependencies, `{"python": "build"}` or
+/// `{"curl": ["build", "test"]}` for the other kinds
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum UsesFromMacos {
+    Runtime(String),
+    Typed(HashMap<String, serde_json::Value>),
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct MacosBound {
+    /// the first macOS release that provides the dependency
+    pub since: Option<String>,
+}
+
+impl PacInfo {
+    /// Describe why the formula is deprecated, if it is
+    pub fn deprecation_note(&self) -> Option<String> {
+        self.deprecated.then(|| {
+            lifecycle_note(
+                "deprecated",
+                self.deprecation_date.as_deref(),
+                self.deprecation_reason.as_deref(),
+            )
+        })
+    }
+
+    /// Describe why the formula is disabled, if it is
+    pub fn disable_note(&self) -> Option<String> {
+        self.disabled.then(|| {
+            lifecycle_note(
+                "disabled",
+                self.disable_date.as_deref(),
+                self.disable_reason.as_deref(),
+            )
+        })
+    }
+}
+
+impl PacInfo {
+    /// Caveats with the Homebrew prefix pointing to our prefix
+    pub fn caveats_with_prefix(&self, prefix: &str) -> Option<String> {
+        self.caveats
+            .as_deref()
+            .map(str::trim)
+            .filter(|c| !c.is_empty())
+            .map(|c| c.replace("$HOMEBREW_PREFIX", prefix))
+    }
+}
+
+fn lifecycle_note(state: &str, date: Option<&str>, reason: Option<&str>) -> String {
+    let mut note = state.to_string();
+    if let Some(date) = date {
+        note.push_str(&format!(" since {date}"));
+    }
+    // reasons are symbols such as `does_not_build` most of the time
+    if let Some(reason) = reason {
+        note.push_str(&format!(": {}", reason.replace('_', " ")));
+    }
+    note
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -68,6 +170,30 @@ pub struct BottleInfo {
     pub files: HashMap<String, File>,
 }
 
+impl BottleInfo {
+    /// Pick the bottle for `arch`, walking back from the running macOS release to
+    /// the oldest one like Homebrew does, then try the `all` bottle.
+    /// Only the bottle of the running release is accepted without `allow_fallback`
+    pub fn select(
+        &self,
+        arch: &str,
+        os: MacOSVersion,
+        allow_fallback: bool,
+    ) -> Option<(String, &File)> {
+        let releases = MacOSVersion::iter()
+            .rev()
+            .filter(|v| *v <= os)
+            .take(if allow_fallback { usize::MAX } else { 1 });
+        for release in releases {
+            let tag = bottle_tag(arch, release);
+            if let Some(file) = self.files.get(&tag) {
+                return Some((tag, file));
+            }
+        }
+        self.files.get("all").map(|file| ("all".to_string(), file))
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct File {
     pub cellar: String,
@@ -94,6 +220,68 @@ pub async fn get_json_api(name: &str) -> Result<PacInfo, CloudError> {
     Ok(pac_info)
 }
 
+/// Only the naming part of the formula index, used to resolve aliases and renamed formulae
+#[derive(Debug, Deserialize)]
+struct FormulaName {
+    name: String,
+    #[serde(default)]
+    aliases: Vec<String>,
+    #[serde(default)]
+    oldnames: Vec<String>,
+}
+
+/// The formula index is quite large, so reuse the cached one for a day
+const INDEX_CACHE_SECS: u64 = 24 * 60 * 60;
+
+async fn get_formula_names() -> Result<Vec<FormulaName>, CloudError> {
+    let path = CACHE_DIR.join("formula.json");
+    let is_fresh = fs::metadata(&path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.elapsed().ok())
+        .is_some_and(|d| d.as_secs() < INDEX_CACHE_SECS);
+    if is_fresh && let Ok(data) = fs::read(&path) {
+        match serde_json::from_slice(&data) {
+            Ok(names) => return Ok(names),
+            Err(e) => eprintln!("Warning: cached formula index is invalid, error: {e}"),
+        }
+    }
+    println!("downloading formula index...");
+    let json = get_all_json_raw().await?;
+    let names: Vec<FormulaName> = serde_json::from_slice(&json)?;
+    if let Err(e) = fs::write(&path, &json) {
+        eprintln!("Warning: can not cache formula index, error: {e}");
+    }
+    Ok(names)
+}
+
+/// Find the canonical name of a formula by its alias or its old name
+pub async fn find_canonical_name(name: &str) -> Result<Option<String>, CloudError> {
+    let names = get_formula_names().await?;
+    let canonical = names
+        .iter()
+        .find(|f| f.aliases.iter().any(|a| a == name))
+        .or_else(|| names.iter().find(|f| f.oldnames.iter().any(|o| o == name)))
+        .map(|f| f.name.clone());
+    Ok(canonical)
+}
+
+/// Like [`get_json_api`], but also accepts aliases (`python3`) and
+/// old names of formulae renamed upstream
+pub async fn resolve_json_api(name: &str) -> Result<PacInfo, CloudError> {
+    match get_json_api(name).await {
+        // only a 404 is reported as an api error
+        Err(CloudError::Api(e)) => match find_canonical_name(name).await? {
+            Some(canonical) => {
+                println!("`{name}` is resolved to `{canonical}`");
+                get_json_api(&canonical).await
+            }
+            None => Err(CloudError::Api(e)),
+        },
+        res => res,
+    }
+}
+
 pub async fn get_json_api_multi<S>(names: &[S]) -> Result<Vec<PacInfo>, CloudError>
 where
     S: AsRef<str>,
@@ -110,7 +298,16 @@ where
 }
 
 pub async fn get_all_json_api() -> Result<Vec<PacInfo>, CloudError> {
-    let url = "https://formulae.brew.sh/api/formula.json";
+    let json = get_all_json_raw().await?;
+    let pac_info: Vec<PacInfo> = serde_json::from_slice(&json)?;
+    Ok(pac_info)
+}
+
+async fn get_all_json_raw() -> Result<Vec<u8>, CloudError> {
+    let root = API_MIRROR
+        .as_deref()
+        .unwrap_or("https://formulae.brew.sh/api");
+    let url = format!("{}/formula.json", root);
     let mut response = CLIENT_WITH_RETRY.get(url).send().await?;
     let status = response.status();
     if !status.is_success() {
@@ -133,8 +330,7 @@ pub async fn get_all_json_api() -> Result<Vec<PacInfo>, CloudError> {
         std::io::Write::write_all(&mut json, &bytes)?;
         progress.inc(bytes.len() as u64);
     }
-    let pac_info: Vec<PacInfo> = serde_json::from_slice(&json)?;
-    Ok(pac_info)
+    Ok(json)
 }
 
 #[derive(Debug, Deserialize)]
@@ -183,6 +379,7 @@ async fn download_with_bar(
     url: &str,
     name: &str,
     sha256: &str,
+    tag: &str,
     pac: &PacInfo,
     progress: ProgressBar,
 ) -> Result<PathBuf, CatError> {
@@ -223,7 +420,7 @@ async fn download_with_bar(
     };
     let mut response = match &*BOTTLES_MIRROR {
         Some(url) => {
-            let url = format!("{url}/{name}-{version}.{}.{build}.tar.gz", ARCH_OS.as_str());
+            let url = format!("{url}/{name}-{version}.{tag}.{build}.tar.gz");
             CLIENT_WITH_RETRY.get(url).send().await?
         }
         None => {
@@ -261,38 +458,32 @@ async fn download_with_bar(
     }
 }
 
-pub async fn download_multi(pacs: &Vec<Rc<PacInfo>>) -> Result<Vec<PathBuf>, CatError> {
+pub async fn download_multi(entries: &[PlanEntry]) -> Result<Vec<PathBuf>, CatError> {
     let multi_bar = MultiProgress::new();
     let mut futs = Vec::new();
-    for pac in pacs.iter() {
-        if let Some(bottle) = &pac.bottle
-            && let Some(bottle) = &bottle.stable
-        {
-            let file = if let Some(file) = bottle.files.get(ARCH_OS.as_str()) {
-                file
-            } else if let Some(file) = bottle.files.get("all") {
-                file
-            } else {
-                return Err(CatError::Pac(format!(
-                    "Package {} has no stable bottle for `{}` or `all` channel",
-                    pac.full_name,
-                    ARCH_OS.as_str()
-                )));
-            };
-            let bar = ProgressBar::hidden();
-            bar.set_style(PROGRESS_STYLE.clone());
-            let bar = multi_bar.add(bar);
-            let fut = download_with_bar(&pac.tap, &file.url, &pac.name, &file.sha256, &pac, bar);
-            futs.push(fut);
-        } else {
-            return Err(CatError::Pac(format!(
-                "Package {} has no stable bottle",
-                pac.full_name,
-            )));
-        }
+    for entry in entries.iter() {
+        let pac = &entry.pac;
+        let file = &entry.bottle_file;
+        let bar = ProgressBar::hidden();
+        bar.set_style(PROGRESS_STYLE.clone());
+        let bar = multi_bar.add(bar);
+        let fut = download_with_bar(
+            &pac.tap,
+            &file.url,
+            &pac.name,
+            &file.sha256,
+            &entry.bottle_tag,
+            pac,
+            bar,
+        );
+        futs.push(fut);
     }
-    let res = futures::future::join_all(futs)
-        .await
+    // dropping the downloads on Ctrl-C cancels them
+    let results = tokio::select! {
+        results = futures::future::join_all(futs) => results,
+        _ = interrupt::interrupted() => return Err(CatError::Interrupted),
+    };
+    let res = results
         .into_iter()
         .collect::<Result<Vec<PathBuf>, CatError>>()?;
     Ok(res)
@@ -319,7 +510,21 @@ fn verify_hash(path: &PathBuf, expected_hash: &str) -> Result<bool, CatError> {
     Ok(result_array == expected_hash)
 }
 
-pub async fn install_pac(req_name: &str) -> Result<(), CatError> {
+#[derive(Debug, Default, Clone, Copy)]
+pub struct InstallOptions {
+    /// install packages even if they are disabled upstream
+    pub force: bool,
+    /// install Homebrew's copies of dependencies provided by macOS
+    pub force_bottle_deps: bool,
+    /// only accept bottles built for the running macOS release
+    pub no_bottle_fallback: bool,
+    /// fail if Homebrew paths are left after relocation
+    pub strict: bool,
+    /// replace files in the prefix that pac did not make, they are put back on rollback
+    pub overwrite: bool,
+}
+
+pub async fn install_pac(req_name: &str, options: InstallOptions) -> Result<(), CatError> {
     let mut tx = SqlTransaction::new().await?;
     if let Some((_, state)) = tx.is_installed(req_name).await? {
         match state {
@@ -335,11 +540,34 @@ pub async fn install_pac(req_name: &str) -> Result<(), CatError> {
             }
         }
     }
-    let pac = get_json_api(req_name).await?;
+    let pac = resolve_json_api(req_name).await?;
+    let req_name = pac.name.clone();
+    migrate_renamed(&pac, &mut tx).await?;
+    if let Some((_, state)) = tx.is_installed(&req_name).await? {
+        match state {
+            PacState::Installed => {
+                println!("Package {} is already installed", req_name);
+                // keep the migrated records
+                tx.commit().await?;
+                return Ok(());
+            }
+            PacState::Broken => {
+                return Err(CatError::Pac(format!(
+                    "package {} is broken, please uninstall it first",
+                    req_name
+                )));
+            }
+        }
+    }
     println!("resolving dependents...");
-    let deps = resolve_depend(pac).await?;
+    let policy = DependPolicy {
+        os: *MACOS_VERSION,
+        force_bottle_deps: options.force_bottle_deps,
+    };
+    let deps = resolve_depend(pac, &policy).await?;
     let mut to_install = Vec::new();
     for dep in deps {
+        migrate_renamed(&dep, &mut tx).await?;
         match tx.is_installed(&dep.name).await? {
             Some((_, state)) => {
                 if let PacState::Broken = state {
@@ -353,10 +581,13 @@ pub async fn install_pac(req_name: &str) -> Result<(), CatError> {
             None => to_install.push(dep),
         }
     }
+    let plan = InstallPlan::new(to_install, &policy, !options.no_bottle_fallback)?;
+    plan.print();
+    plan.check(options.force)?;
     println!("detecting conflicts...");
-    detect_conflicts(&to_install, &mut tx).await?;
+    detect_conflicts(&plan.entries, &mut tx).await?;
     println!("downloading pacs...");
-    let paths = download_multi(&to_install).await?;
+    let paths = download_multi(&plan.entries).await?;
     let mut temp_paths = DropGuard::new(Vec::<PathBuf>::new(), |temp_paths| {
         // clean temp dir
         println!("cleaning temp dirs...");
@@ -370,13 +601,17 @@ pub async fn install_pac(req_name: &str) -> Result<(), CatError> {
         }
         println!("temp dirs are removed!");
     });
-    let mut restore_guard = DropGuard::new(Vec::<Vec<PathBuf>>::new(), |installed_files| {
+    // dropped after `restore_guard`, an install killed before either of them
+    // runs is left to `pac repair`
+    let journal = Journal::begin(Action::Install)?;
+    let mut restore_guard = DropGuard::new(Vec::<InstalledFiles>::new(), |installed_files| {
         eprintln!("encounter an error, restoring install dir");
         // also remove dirs
         let mut dirs = BTreeSet::new();
-        let pac_path = Path::new(PAC_PATH);
-        for paths in installed_files.iter() {
-            for p in paths.iter() {
+        let pac_path = Path::new(pac_root());
+        for installed in installed_files.iter() {
+            // planned links that were never created may be files of others
+            for p in installed.files.iter().chain(installed.created.iter()) {
                 let mut ancestors = p.ancestors();
                 // skip itself
                 ancestors.next();
@@ -386,7 +621,12 @@ pub async fn install_pac(req_name: &str) -> Result<(), CatError> {
                 {
                     dirs.insert(CmpPath(parent));
                 }
-                if let Err(e) = remove_file_force(&p) {
+            }
+            // files of a keg that was not published yet do not exist
+            for p in installed.files.iter() {
+                if let Err(e) = remove_file_force(p)
+                    && e.kind() != io::ErrorKind::NotFound
+                {
                     eprintln!(
                         "Warning: Can not remove installed file: {}, error: {e}",
                         p.display()
@@ -394,18 +634,42 @@ pub async fn install_pac(req_name: &str) -> Result<(), CatError> {
                 }
             }
         }
+        let mut restored = true;
+        for installed in installed_files.iter().rev() {
+            if let Err(e) = unpublish_links(&installed.created, &installed.replaced) {
+                eprintln!("Warning: {e}");
+                restored = false;
+            }
+        }
         for dir in dirs {
-            if let Err(e) = remove_dir_force(&*dir) {
+            // dirs holding restored files stay
+            if let Err(e) = remove_dir_force(&*dir)
+                && !matches!(
+                    e.kind(),
+                    io::ErrorKind::DirectoryNotEmpty | io::ErrorKind::NotFound
+                )
+            {
                 eprintln!(
                     "Warning: Can not remove installed dir: {}, error: {e}",
                     dir.0.display()
                 )
             }
         }
+        // backups that are not restored stay in the scratch dir for `pac repair`
+        if !restored {
+            journal::keep();
+            return;
+        }
+        let _ = remove_dir_recursively_force(scratch_dir());
         println!("recovery finished!");
     });
+    let is_multi = plan.entries.len() > 1;
+    let mut all_caveats = Vec::new();
+    let mut all_warnings = Vec::new();
     // install pacs
-    for (pac, mut path) in zip(to_install, paths) {
+    for (entry, mut path) in zip(plan.entries, paths) {
+        interrupt::check()?;
+        let pac = &entry.pac;
         println!("installing {}", pac.full_name);
         println!("loading downloaded files");
         let downloaded_file = fs::File::open(&path)?;
@@ -430,39 +694,134 @@ pub async fn install_pac(req_name: &str) -> Result<(), CatError> {
         };
         temp_dir.push(&name_version);
         println!("preprocessing...");
-        before_install(&temp_dir, &name_version)?;
+        let preprocessed = before_install(&temp_dir)?;
+        for warning in preprocessed.warnings.iter() {
+            eprintln!("Warning: {warning}");
+        }
+        let leftovers = audit_dir(&temp_dir)?;
+        if !leftovers.is_empty() {
+            eprintln!("Warning: Homebrew paths are left in {}:", pac.name);
+            print_leftovers(&leftovers);
+            if options.strict {
+                return Err(CatError::Relocate(format!(
+                    "{} Homebrew paths are left in {}",
+                    leftovers.len(),
+                    pac.name
+                )));
+            }
+        }
+        interrupt::check()?;
         println!("preprocess done, installing...");
-        let installed_files = Vec::new();
-        restore_guard.push(installed_files);
-        let installed_files = restore_guard.last_mut().unwrap();
-        // we should ensure the path is not conflicted before calling install.
-        // implmentation is in the function below
-        install(&temp_dir, installed_files, &mut tx).await?;
-        let files = &pac.bottle.as_ref().unwrap().stable.as_ref().unwrap().files;
-        let sha256 = if let Some(file) = files.get(ARCH_OS.as_str()) {
-            &file.sha256
-        } else if let Some(file) = files.get("all") {
-            &file.sha256
-        } else {
-            unreachable!("channel is only all or {}", ARCH_OS.as_str())
-        };
-        tx.install_a_pac(
-            &pac,
-            pac.versions.stable.as_ref().unwrap(),
-            &pac.bottle.as_ref().unwrap().stable.as_ref().unwrap(),
-            sha256,
-            pac.name == req_name,
-            &installed_files,
-        )
+        journal.sync()?;
+        restore_guard.push(InstalledFiles::default());
+        let installed = restore_guard.last_mut().unwrap();
+        // every check runs against the staged keg, the prefix is only touched by `publish`
+        let staged = stage(&temp_dir, &name_version, pac.keg_only, installed, &mut tx).await?;
+        verify_load_paths(&pac.name, &preprocessed.load_paths, installed, &mut tx).await?;
+        check_existing(&installed.links, options.overwrite)?;
+        interrupt::check()?;
+        staged.publish(installed)?;
+        let linkage = cross_linkage(&pac.name, &installed.files, &mut tx).await?;
+        let caveats = pac.caveats_with_prefix(pac_root());
+        tx.install_a_pac(&PacRecord {
+            pac,
+            version: pac.versions.stable.as_ref().unwrap(),
+            bottle: pac.bottle.as_ref().unwrap().stable.as_ref().unwrap(),
+            sha256: &entry.bottle_file.sha256,
+            bottle_tag: &entry.bottle_tag,
+            explicit: pac.name == req_name,
+            keg_only: pac.keg_only,
+            dependencies: &entry.deps,
+            installed_files: &installed.files,
+            links: &installed.links,
+            linkage: &linkage,
+            caveats: caveats.as_deref(),
+        })
         .await?;
         println!("Package {} is installed now", pac.full_name);
+        if pac.keg_only {
+            println!(
+                "{} is keg-only, it is not linked into {}, use `pac link --force {}` to link it",
+                pac.name,
+                pac_root(),
+                pac.name
+            );
+        }
+        if let Some(caveats) = caveats {
+            println!("==> Caveats of {}\n{}\n", pac.name, caveats);
+            all_caveats.push((pac.name.clone(), caveats));
+        }
+        if !preprocessed.warnings.is_empty() {
+            all_warnings.push((pac.name.clone(), preprocessed.warnings));
+        }
     }
     tx.commit().await?;
     // IMPORTANT: cancel the drop guard
     restore_guard.into_inner();
+    // backups of replaced files are not needed anymore
+    if let Err(e) = remove_dir_recursively_force(scratch_dir()) {
+        eprintln!("Warning: Can not remove backups, error: {e}");
+    }
+    if is_multi && !all_caveats.is_empty() {
+        println!("==> Caveats summary");
+        for (name, caveats) in all_caveats {
+            println!("==> {name}\n{caveats}\n");
+        }
+    }
+    if is_multi && !all_warnings.is_empty() {
+        eprintln!("==> Warnings summary");
+        for (name, warnings) in all_warnings {
+            eprintln!("==> {name}");
+            for warning in warnings {
+                eprintln!("  {warning}");
+            }
+        }
+    }
     Ok(())
 }
 
+#[test]
+fn test_select_bottle() {
+    let file = |url: &str| File {
+        cellar: ":any".to_string(),
+        url: url.to_string(),
+        sha256: String::new(),
+    };
+    let mut bottle = BottleInfo {
+        rebuild: 0,
+        files: HashMap::from([
+            ("arm64_sonoma".to_string(), file("arm64_sonoma")),
+            ("arm64_ventura".to_string(), file("arm64_ventura")),
+            ("sonoma".to_string(), file("sonoma")),
+        ]),
+    };
+    let (tag, _) = bottle.select("arm64", MacOSVersion::Tahoe, true).unwrap();
+    assert_eq!(tag, "arm64_sonoma");
+    let (tag, _) = bottle.select("arm64", MacOSVersion::Sonoma, true).unwrap();
+    assert_eq!(tag, "arm64_sonoma");
+    let (tag, _) = bottle.select("arm64", MacOSVersion::Ventura, true).unwrap();
+    assert_eq!(tag, "arm64_ventura");
+    assert!(
+        bottle
+            .select("arm64", MacOSVersion::Monterey, true)
+            .is_none()
+    );
+    assert!(
+        bottle
+            .select("arm64", MacOSVersion::Sequoia, false)
+            .is_none()
+    );
+    let (tag, _) = bottle
+        .select("x86_64", MacOSVersion::Sequoia, true)
+        .unwrap();
+    assert_eq!(tag, "sonoma");
+    bottle.files.insert("all".to_string(), file("all"));
+    let (tag, _) = bottle
+        .select("arm64", MacOSVersion::Sequoia, false)
+        .unwrap();
+    assert_eq!(tag, "all");
+}
+
 #[tokio::test]
 async fn test_get_json_api() {
     let res = get_json_api("wgett").await;
@@ -3,6 +3,9 @@ use clap::{Parser, Subcommand};
 #[derive(Parser)]
 #[command(name = "pac", version = "0.1.0", about = "A fast package manager")]
 pub struct Cli {
+    /// Install root, overrides `PAC_ROOT` and the config file
+    #[arg(long, global = true)]
+    pub root: Option<String>,
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -14,6 +17,21 @@ pub enum Commands {
         /// Package name to install
         #[arg(help = "Package name")]
         name: String,
+        /// Install packages even if they are disabled upstream
+        #[arg(long)]
+        force: bool,
+        /// Install Homebrew's copies of dependencies that macOS provides
+        #[arg(long)]
+        force_bottle_deps: bool,
+        /// Do not use bottles built for older macOS releases
+        #[arg(long)]
+        no_bottle_fallback: bool,
+        /// Fail if Homebrew paths are left after relocation
+        #[arg(long)]
+        strict: bool,
+        /// Replace files in the prefix that were not installed by pac
+        #[arg(long)]
+        overwrite: bool,
     },
 
     /// Uninstall a package
@@ -21,11 +39,54 @@ pub enum Commands {
         /// Package name to uninstall
         #[arg(help = "Package name")]
         name: String,
+        /// Uninstall it even if installed binaries load its libraries
+        #[arg(long)]
+        force: bool,
     },
 
     /// List installed packages
     List,
 
+    /// Link an installed package into the prefix
+    Link {
+        /// Package name to link
+        #[arg(help = "Package name")]
+        name: String,
+        /// Link keg-only packages too
+        #[arg(long)]
+        force: bool,
+        /// Replace files in the prefix that were not installed by pac
+        #[arg(long)]
+        overwrite: bool,
+    },
+
+    /// Remove the links of a package from the prefix, but keep it installed
+    Unlink {
+        /// Package name to unlink
+        #[arg(help = "Package name")]
+        name: String,
+    },
+
+    /// List outdated packages and packages deprecated upstream
+    Outdated,
+
+    /// List Homebrew paths left in the files of an installed package
+    Audit {
+        /// Package name
+        #[arg(help = "Package name")]
+        name: String,
+    },
+
+    /// Check the dylibs loaded by installed binaries
+    Linkage {
+        /// Package name, all installed packages if omitted
+        #[arg(help = "Package name")]
+        name: Option<String>,
+    },
+
+    /// Clean up after an install or uninstall that was killed
+    Repair,
+
     /// Show package info
     Info {
         /// Package name
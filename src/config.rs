@@ -0,0 +1,137 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use serde::Deserialize;
+
+use crate::{PAC_PATH, errors::CatError};
+
+/// `config.toml` in the config dir, such as `~/Library/Application Support/pac/config.toml`
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    /// where packages are installed
+    pub root: Option<String>,
+    /// the database file, it can be shared by several roots
+    pub database: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub struct Config {
+    pub root: String,
+    pub database: PathBuf,
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+impl Config {
+    /// The first one wins: command line, `PAC_ROOT` and `PAC_DB`, the config file, the default.
+    /// Packages go to `/opt/pac` and the database lives in `<root>/PacData` by default
+    pub fn resolve(
+        root: Option<String>,
+        env: ConfigFile,
+        file: ConfigFile,
+    ) -> Result<Self, CatError> {
+        let root = root
+            .or(env.root)
+            .or(file.root)
+            .unwrap_or_else(|| PAC_PATH.to_string());
+        let trimmed = root.trim_end_matches('/');
+        if !Path::new(trimmed).is_absolute() {
+            return Err(CatError::Pac(format!(
+                "install root must be an absolute path: {root}"
+            )));
+        }
+        let database = env
+            .database
+            .or(file.database)
+            .unwrap_or_else(|| Path::new(trimmed).join("PacData/pacs.sqlite"));
+        Ok(Self {
+            root: trimmed.to_string(),
+            database,
+        })
+    }
+
+    fn load(root: Option<String>) -> Result<Self, CatError> {
+        let env = ConfigFile {
+            root: std::env::var("PAC_ROOT").ok().filter(|r| !r.is_empty()),
+            database: std::env::var_os("PAC_DB")
+                .filter(|d| !d.is_empty())
+                .map(PathBuf::from),
+        };
+        let path = std::env::var_os("PAC_CONFIG")
+            .map(PathBuf::from)
+            .or_else(|| dirs::config_dir().map(|d| d.join("pac/config.toml")));
+        let file = match path {
+            Some(path) if path.exists() => {
+                let content = fs::read_to_string(&path)?;
+                toml::from_str(&content).map_err(|e| {
+                    CatError::Pac(format!("Can not read config {}: {e}", path.display()))
+                })?
+            }
+            _ => ConfigFile::default(),
+        };
+        Self::resolve(root, env, file)
+    }
+}
+
+/// Load the config once, before anything touches the install root or the database
+pub fn init_config(root: Option<String>) -> Result<&'static Config, CatError> {
+    let config = Config::load(root)?;
+    Ok(CONFIG.get_or_init(|| config))
+}
+
+pub fn config() -> &'static Config {
+    CONFIG.get_or_init(|| match Config::load(None) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    })
+}
+
+/// The install root of this invocation
+pub fn pac_root() -> &'static str {
+    &config().root
+}
+
+#[test]
+fn test_resolve_config() {
+    let file = || ConfigFile {
+        root: Some("/Users/someone/.local/pac".to_string()),
+        database: Some(PathBuf::from("/Users/someone/.local/pacs.sqlite")),
+    };
+    let config = Config::resolve(None, ConfigFile::default(), ConfigFile::default()).unwrap();
+    assert_eq!(config.root, PAC_PATH);
+    assert_eq!(
+        config.database,
+        Path::new(PAC_PATH).join("PacData/pacs.sqlite")
+    );
+    let config = Config::resolve(None, ConfigFile::default(), file()).unwrap();
+    assert_eq!(config.root, "/Users/someone/.local/pac");
+    // the database follows the root unless it is set
+    let config = Config::resolve(
+        Some("/Users/someone/.local/pac/".to_string()),
+        ConfigFile::default(),
+        ConfigFile::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        config.database,
+        Path::new("/Users/someone/.local/pac/PacData/pacs.sqlite")
+    );
+    let env = ConfigFile {
+        root: Some("/tmp/pac-env/".to_string()),
+        database: None,
+    };
+    let config = Config::resolve(Some("/tmp/pac-cli".to_string()), env, file()).unwrap();
+    assert_eq!(config.root, "/tmp/pac-cli");
+    assert_eq!(
+        config.database,
+        Path::new("/Users/someone/.local/pacs.sqlite")
+    );
+    assert!(Config::resolve(Some("pac".to_string()), ConfigFile::default(), file()).is_err());
+}
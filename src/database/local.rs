@@ -1,12 +1,13 @@
 use crate::{
-    PAC_PATH,
     brew_api::{BottleInfo, PacInfo},
+    config::{config, pac_root},
     errors::CatError,
     macos::version::ARCH,
     sql,
 };
 use sqlx::{Decode, Pool, Sqlite, SqlitePool, prelude::Type, sqlite::SqliteConnectOptions};
 use std::{
+    collections::BTreeMap,
     fs,
     path::{Path, PathBuf},
     sync::LazyLock,
@@ -38,20 +39,127 @@ impl<'r> Decode<'r, Sqlite> for PacState {
     }
 }
 
+#[derive(Debug, sqlx::FromRow)]
+pub struct InstalledPac {
+    pub name: String,
+    pub version: String,
+    pub arch: String,
+    pub bottle_tag: Option<String>,
+    pub explicit: bool,
+    pub keg_only: bool,
+    pub install_time: i64,
+    pub state: PacState,
+    pub caveats: Option<String>,
+}
+
+/// Everything we record about a package when it is installed
+pub struct PacRecord<'a> {
+    pub pac: &'a PacInfo,
+    pub version: &'a str,
+    pub bottle: &'a BottleInfo,
+    pub sha256: &'a str,
+    pub bottle_tag: &'a str,
+    pub explicit: bool,
+    pub keg_only: bool,
+    pub dependencies: &'a [String],
+    pub installed_files: &'a [PathBuf],
+    pub links: &'a BTreeMap<PathBuf, PathBuf>,
+    /// binaries of the package and the dylibs of other packages they load
+    pub linkage: &'a [(PathBuf, PathBuf)],
+    pub caveats: Option<&'a str>,
+}
+
+async fn set_user_version<'e, E>(executor: E, version: usize) -> Result<(), CatError>
+where
+    E: sqlx::SqliteExecutor<'e>,
+{
+    // PRAGMA does not take parameters
+    sqlx::query(&format!("PRAGMA user_version = {}", version))
+        .execute(executor)
+        .await?;
+    Ok(())
+}
+
+/// Bring the schema to the version of this binary, every migration runs in its own
+/// transaction. An existing database is copied to `<db>.v<version>.bak` first
+async fn migrate(pool: &SqlitePool, path: &Path) -> Result<(), CatError> {
+    let latest = sql::MIGRATIONS.len();
+    let mut version: usize = sqlx::query_scalar::<_, i64>("PRAGMA user_version")
+        .fetch_one(pool)
+        .await?
+        .try_into()
+        .unwrap_or(usize::MAX);
+    if version > latest {
+        return Err(CatError::Pac(format!(
+            "The database {} has schema version {}, but this pac only supports up to {}\n\
+            Please upgrade pac",
+            path.display(),
+            version,
+            latest
+        )));
+    }
+    // databases created before `user_version` was tracked have the baseline schema
+    if version == 0
+        && sqlx::query_scalar(sql::SELECT_HAS_TABLE)
+            .bind("installed_packages")
+            .fetch_one(pool)
+            .await?
+    {
+        version = 1;
+    }
+    // a new database has nothing to back up
+    if version > 0 && version < latest {
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(format!(".v{}.bak", version));
+        let backup = PathBuf::from(backup);
+        // left by an earlier migration that failed, it is the only copy
+        // of the database before it
+        if fs::exists(&backup)? {
+            println!(
+                "Migrating the database to version {}, the old one is already kept in {}",
+                latest,
+                backup.display()
+            );
+        } else {
+            println!(
+                "Migrating the database to version {}, the old one is kept in {}",
+                latest,
+                backup.display()
+            );
+            sqlx::query("VACUUM INTO $1")
+                .bind(backup.to_string_lossy())
+                .execute(pool)
+                .await?;
+        }
+    }
+    for (i, migration) in sql::MIGRATIONS.iter().enumerate().skip(version) {
+        let mut tx = pool.begin().await?;
+        sqlx::query(migration).execute(&mut *tx).await?;
+        set_user_version(&mut *tx, i + 1).await?;
+        tx.commit().await?;
+    }
+    Ok(())
+}
+
 pub async fn init_db() -> Result<(), CatError> {
-    let path = Path::new(PAC_PATH).join("PacData");
-    if fs::metadata(path.join("pacs.sqlite")).is_err() {
-        fs::create_dir_all(path)?;
+    let path = &config().database;
+    if fs::metadata(path).is_err() {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).inspect_err(|_| {
+                eprintln!(
+                    "Can not create {}, set `PAC_DB` to put the database somewhere else",
+                    parent.display()
+                )
+            })?;
+        }
         println!("Database file not found, creating a new one...");
-        let pool = SqlitePool::connect_with(SQL_OPTS.clone()).await?;
-        sqlx::query(sql::INIT_DB).execute(&pool).await?;
     }
-    Ok(())
+    migrate(&SQL_POOL, path).await
 }
 
 static SQL_OPTS: LazyLock<SqliteConnectOptions> = LazyLock::new(|| {
     SqliteConnectOptions::new()
-        .filename(format!("{PAC_PATH}/PacData/pacs.sqlite"))
+        .filename(&config().database)
         .create_if_missing(true)
 });
 
@@ -67,6 +175,19 @@ impl SqlTransaction {
         let tx = SQL_POOL.begin().await?;
         Ok(Self { tx })
     }
+
+    /// A transaction on an empty database in memory, for tests
+    #[cfg(test)]
+    pub async fn memory() -> Result<Self, CatError> {
+        // every connection to `:memory:` opens a database of its own
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(SqliteConnectOptions::new().filename(":memory:"))
+            .await?;
+        migrate(&pool, Path::new(":memory:")).await?;
+        let tx = pool.begin().await?;
+        Ok(Self { tx })
+    }
     pub async fn commit(self) -> Result<(), CatError> {
         self.tx.commit().await?;
         Ok(())
@@ -75,7 +196,7 @@ impl SqlTransaction {
     pub async fn is_installed(&mut self, name: &str) -> Result<Option<(i64, PacState)>, CatError> {
         let id_state = sqlx::query_as::<_, (i64, PacState)>(sql::SELECT_PAC_ID)
             .bind(name)
-            .bind(PAC_PATH)
+            .bind(pac_root())
             .fetch_optional(&mut *self.tx)
             .await?;
         Ok(id_state)
@@ -91,11 +212,29 @@ impl SqlTransaction {
 
     pub async fn get_pac_names(&mut self) -> Result<Vec<String>, CatError> {
         let names: Vec<String> = sqlx::query_scalar(sql::SELECT_PAC_NAMES)
+            .bind(pac_root())
             .fetch_all(&mut *self.tx)
             .await?;
         Ok(names)
     }
 
+    pub async fn get_pac_info(&mut self, name: &str) -> Result<Option<InstalledPac>, CatError> {
+        let info = sqlx::query_as::<_, InstalledPac>(sql::SELECT_PAC_INFO)
+            .bind(name)
+            .bind(pac_root())
+            .fetch_optional(&mut *self.tx)
+            .await?;
+        Ok(info)
+    }
+
+    pub async fn get_pac_versions(&mut self) -> Result<Vec<(String, String)>, CatError> {
+        let pacs = sqlx::query_as::<_, (String, String)>(sql::SELECT_PAC_VERSIONS)
+            .bind(pac_root())
+            .fetch_all(&mut *self.tx)
+            .await?;
+        Ok(pacs)
+    }
+
     pub async fn get_installed_files(&mut self, id: i64) -> Result<Vec<PathBuf>, CatError> {
         let file_list: Vec<String> = sqlx::query_scalar(sql::SELECT_INSTALLED_FILE)
             .bind(id)
@@ -105,9 +244,99 @@ impl SqlTransaction {
         Ok(path_list)
     }
 
+    /// Links of a package in the prefix and their targets
+    pub async fn get_links(&mut self, id: i64) -> Result<Vec<(PathBuf, PathBuf)>, CatError> {
+        let links: Vec<(String, String)> = sqlx::query_as(sql::SELECT_LINKS)
+            .bind(id)
+            .fetch_all(&mut *self.tx)
+            .await?;
+        let links = links
+            .into_iter()
+            .map(|(path, target)| (PathBuf::from(path), PathBuf::from(target)))
+            .collect();
+        Ok(links)
+    }
+
+    pub async fn get_link_target<P>(&mut self, path: P) -> Result<Option<PathBuf>, CatError>
+    where
+        P: AsRef<Path>,
+    {
+        let target: Option<String> = sqlx::query_scalar(sql::SELECT_LINK_TARGET)
+            .bind(path.as_ref().to_string_lossy())
+            .fetch_optional(&mut *self.tx)
+            .await?;
+        Ok(target.map(PathBuf::from))
+    }
+
+    pub async fn insert_links(
+        &mut self,
+        id: i64,
+        links: &BTreeMap<PathBuf, PathBuf>,
+    ) -> Result<(), CatError> {
+        for (link, target) in links {
+            sqlx::query(sql::INSERT_LINK)
+                .bind(id)
+                .bind(link.to_string_lossy())
+                .bind(target.to_string_lossy())
+                .execute(&mut *self.tx)
+                .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn delete_link<P>(&mut self, path: P) -> Result<(), CatError>
+    where
+        P: AsRef<Path>,
+    {
+        sqlx::query(sql::DELETE_LINK)
+            .bind(path.as_ref().to_string_lossy())
+            .execute(&mut *self.tx)
+            .await?;
+        Ok(())
+    }
+
+    /// The package owning an installed file, links are not followed
+    pub async fn get_path_owner<P>(&mut self, path: P) -> Result<Option<String>, CatError>
+    where
+        P: AsRef<Path>,
+    {
+        let owner: Option<String> = sqlx::query_scalar(sql::SELECT_PATH_OWNER)
+            .bind(path.as_ref().to_string_lossy())
+            .fetch_optional(&mut *self.tx)
+            .await?;
+        Ok(owner)
+    }
+
+    /// Dependencies recorded when the package was installed
+    pub async fn get_deps(&mut self, id: i64) -> Result<Vec<String>, CatError> {
+        let deps: Vec<String> = sqlx::query_scalar(sql::SELECT_DEPS)
+            .bind(id)
+            .fetch_all(&mut *self.tx)
+            .await?;
+        Ok(deps)
+    }
+
+    /// Binaries of other packages that load the dylibs of a package,
+    /// as `(package, binary, dylib)`
+    pub async fn get_linked_by(
+        &mut self,
+        id: i64,
+    ) -> Result<Vec<(String, PathBuf, PathBuf)>, CatError> {
+        let rows: Vec<(String, String, String)> = sqlx::query_as(sql::SELECT_LINKED_BY)
+            .bind(id)
+            .fetch_all(&mut *self.tx)
+            .await?;
+        let rows = rows
+            .into_iter()
+            .map(|(name, binary, dylib)| (name, PathBuf::from(binary), PathBuf::from(dylib)))
+            .collect();
+        Ok(rows)
+    }
+
     pub async fn get_reverse_deps(&mut self, name: &str) -> Result<Vec<String>, CatError> {
         let rev_deps: Vec<i64> = sqlx::query_scalar(sql::SELECT_REVERSE_DEP)
             .bind(name)
+            .bind(pac_root())
             .fetch_all(&mut *self.tx)
             .await?;
         let mut deps_name = Vec::new();
@@ -129,37 +358,33 @@ impl SqlTransaction {
         Ok(exists == 1)
     }
 
-    pub async fn install_a_pac(
-        &mut self,
-        pac: &PacInfo,
-        version: &str,
-        bottle: &BottleInfo,
-        sha256: &str,
-        explict: bool,
-        installed_files: &[PathBuf],
-    ) -> Result<(), CatError> {
+    pub async fn install_a_pac(&mut self, record: &PacRecord<'_>) -> Result<(), CatError> {
+        let pac = record.pac;
         let time = std::time::SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("unix epoch is always earlier than now")
             .as_secs() as i64;
         sqlx::query(sql::INSERT_PAC)
             .bind(&pac.name)
-            .bind(version)
-            .bind(bottle.rebuild)
+            .bind(record.version)
+            .bind(record.bottle.rebuild)
             .bind(ARCH)
             .bind("stable")
-            .bind(PAC_PATH)
-            .bind(explict as u8)
+            .bind(pac_root())
+            .bind(record.explicit as u8)
             .bind(time)
-            .bind(sha256)
+            .bind(record.sha256)
+            .bind(record.caveats)
+            .bind(record.bottle_tag)
+            .bind(record.keg_only as u8)
             .execute(&mut *self.tx)
             .await?;
         let pac_id = sqlx::query_scalar::<_, i64>(sql::SELECT_PAC_ID)
             .bind(&pac.name)
-            .bind(PAC_PATH)
+            .bind(pac_root())
             .fetch_one(&mut *self.tx)
             .await?;
-        for dep in &pac.dependencies {
+        for dep in record.dependencies {
             sqlx::query(sql::INSERT_DEP)
                 .bind(pac_id)
                 .bind(dep)
@@ -173,13 +398,22 @@ impl SqlTransaction {
                 .execute(&mut *self.tx)
                 .await?;
         }
-        for file_path in installed_files {
+        for file_path in record.installed_files {
             sqlx::query(sql::INSERT_INSTALLED_FILE)
                 .bind(pac_id)
                 .bind(file_path.to_string_lossy())
                 .execute(&mut *self.tx)
                 .await?;
         }
+        self.insert_links(pac_id, record.links).await?;
+        for (binary, dylib) in record.linkage {
+            sqlx::query(sql::INSERT_LINKAGE)
+                .bind(pac_id)
+                .bind(binary.to_string_lossy())
+                .bind(dylib.to_string_lossy())
+                .execute(&mut *self.tx)
+                .await?;
+        }
         Ok(())
     }
 
@@ -201,10 +435,117 @@ impl SqlTransaction {
         Ok(())
     }
 
+    /// Move the records of a formula renamed upstream to its new name,
+    /// including the dependency records of its dependents
+    pub async fn rename_pac(&mut self, old_name: &str, new_name: &str) -> Result<(), CatError> {
+        sqlx::query(sql::RENAME_PAC)
+            .bind(old_name)
+            .bind(new_name)
+            .bind(pac_root())
+            .execute(&mut *self.tx)
+            .await?;
+        sqlx::query(sql::RENAME_DEP)
+            .bind(old_name)
+            .bind(new_name)
+            .bind(pac_root())
+            .execute(&mut *self.tx)
+            .await?;
+        Ok(())
+    }
+
     pub async fn get_orphan_pacs(&mut self) -> Result<Vec<(i64, String, PacState)>, CatError> {
         let rows = sqlx::query_as::<_, (i64, String, PacState)>(sql::SELECT_ORPHAN_PAC)
+            .bind(pac_root())
             .fetch_all(&mut *self.tx)
             .await?;
         Ok(rows)
     }
 }
+
+#[tokio::test]
+async fn test_migrate() {
+    let latest = sql::MIGRATIONS.len() as i64;
+    let path = std::env::temp_dir().join(format!("pac-test-{}.sqlite", std::process::id()));
+    let memory = async || {
+        // one connection, every connection to `:memory:` opens a database of its own
+        sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(SqliteConnectOptions::new().filename(":memory:"))
+            .await
+            .unwrap()
+    };
+    let user_version = async |pool: &SqlitePool| -> i64 {
+        sqlx::query_scalar("PRAGMA user_version")
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    };
+    let has_new_schema = async |pool: &SqlitePool| {
+        sqlx::query("SELECT caveats, bottle_tag, keg_only FROM installed_packages")
+            .fetch_all(pool)
+            .await
+            .is_ok()
+            && sqlx::query("SELECT * FROM links")
+                .fetch_all(pool)
+                .await
+                .is_ok()
+            && sqlx::query("SELECT * FROM linkage")
+                .fetch_all(pool)
+                .await
+                .is_ok()
+    };
+
+    // a new database gets every migration
+    let pool = memory().await;
+    migrate(&pool, &path).await.unwrap();
+    assert_eq!(user_version(&pool).await, latest);
+    assert!(has_new_schema(&pool).await);
+
+    // a database from before `user_version` was tracked has the baseline schema
+    let pool = memory().await;
+    sqlx::query(sql::MIGRATIONS[0])
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query(
+        "INSERT INTO installed_packages (name, version, arch, channel, install_root, explicit, \
+        install_time, update_time, checksum) VALUES ('fish', '4.1.2', 'arm64', 'stable', '/opt/pac', 1, 0, 0, '')",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    assert_eq!(user_version(&pool).await, 0);
+    assert!(!has_new_schema(&pool).await);
+    migrate(&pool, &path).await.unwrap();
+    assert_eq!(user_version(&pool).await, latest);
+    assert!(has_new_schema(&pool).await);
+    let names: Vec<String> = sqlx::query_scalar("SELECT name FROM installed_packages")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+    assert_eq!(names, ["fish"]);
+    // the backup has the baseline schema and the package
+    let mut backup = path.into_os_string();
+    backup.push(".v1.bak");
+    let backup = PathBuf::from(backup);
+    let old = SqlitePool::connect_with(SqliteConnectOptions::new().filename(&backup))
+        .await
+        .unwrap();
+    assert_eq!(user_version(&old).await, 0);
+    assert!(!has_new_schema(&old).await);
+    let names: Vec<String> = sqlx::query_scalar("SELECT name FROM installed_packages")
+        .fetch_all(&old)
+        .await
+        .unwrap();
+    assert_eq!(names, ["fish"]);
+    old.close().await;
+    fs::remove_file(&backup).unwrap();
+
+    // a newer pac wrote it
+    set_user_version(&pool, latest as usize + 1).await.unwrap();
+    let Err(CatError::Pac(reason)) = migrate(&pool, Path::new("pac.sqlite")).await else {
+        panic!("expected a newer schema to be refused");
+    };
+    assert!(reason.contains("Please upgrade pac"));
+    assert_eq!(user_version(&pool).await, latest + 1);
+}
@@ -1,5 +1,6 @@
 use std::borrow::Cow;
 
+use apple_codesign::AppleCodesignError;
 use arwen::macho::MachoError;
 use thiserror::Error;
 
@@ -11,14 +12,28 @@ pub enum CatError {
     Hash(String),
     #[error("Modify macho error: {0}")]
     Macho(#[from] MachoError),
+    #[error("Code signature error: {0}")]
+    Codesign(Box<AppleCodesignError>),
+    #[error("Invalid code signature: {0}")]
+    Signature(String),
     #[error("Package error: {0}")]
     Pac(String),
+    #[error("Relocate error: {0}")]
+    Relocate(String),
+    #[error("Interrupted by Ctrl-C")]
+    Interrupted,
     #[error("Task error: {0}")]
     Task(String),
     #[error("Database error: {0}")]
     Database(#[from] sqlx::Error),
 }
 
+impl From<AppleCodesignError> for CatError {
+    fn from(value: AppleCodesignError) -> Self {
+        Self::Codesign(Box::new(value))
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum CloudError {
     #[error("Deserialize error: {0}")]
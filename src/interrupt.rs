@@ -0,0 +1,63 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use tokio::sync::Notify;
+
+use crate::{errors::CatError, package::journal};
+
+/// Exit status after Ctrl-C, like shells use for SIGINT
+pub const EXIT_INTERRUPTED: u8 = 130;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static NOTIFY: Notify = Notify::const_new();
+
+/// Handle Ctrl-C: the first one asks the running operation to stop after the
+/// current file operation and roll back, the second one exits at once
+pub fn listen() {
+    tokio::spawn(async {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        NOTIFY.notify_waiters();
+        eprintln!("\nInterrupted, stopping after the current file operation...");
+        eprintln!("Press Ctrl-C again to abort without cleaning up");
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        eprintln!("\nAborted");
+        if let Some(path) = journal::active_path() {
+            eprintln!(
+                "Changes to the prefix are left as recorded in {}, run `pac repair` to clean them up",
+                path.display()
+            );
+        }
+        eprintln!(
+            "Extracted packages may be left in {}",
+            std::env::temp_dir().display()
+        );
+        std::process::exit(EXIT_INTERRUPTED as i32);
+    });
+}
+
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Stop here if Ctrl-C is pressed, so the caller rolls back
+pub fn check() -> Result<(), CatError> {
+    if is_interrupted() {
+        return Err(CatError::Interrupted);
+    }
+    Ok(())
+}
+
+/// Resolves when Ctrl-C is pressed, to cancel a future with `select!`
+pub async fn interrupted() {
+    let notified = NOTIFY.notified();
+    tokio::pin!(notified);
+    notified.as_mut().enable();
+    if is_interrupted() {
+        return;
+    }
+    notified.await;
+}
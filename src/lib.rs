@@ -5,15 +5,18 @@ use reqwest_retry::{RetryTransientMiddleware, policies::ExponentialBackoff};
 
 pub mod brew_api;
 pub mod cli;
+pub mod config;
 pub mod database;
 pub mod errors;
+pub mod interrupt;
 pub mod macos;
 pub mod package;
 pub mod scopeguard;
 pub mod sql;
 
+/// The default install root, see [`config`] for how to change it
 pub const PAC_PATH: &str = "/opt/pac";
-pub const CACHE_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
+pub static CACHE_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
     let mut dir = match dirs::cache_dir() {
         Some(d) => d,
         None => {
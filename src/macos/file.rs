@@ -1,5 +1,11 @@
-use crate::{database::local::SqlTransaction, errors::CatError, package::install::DIR_TO_INSTALL};
+use crate::{
+    database::local::SqlTransaction,
+    errors::CatError,
+    interrupt,
+    package::journal::{self, Entry},
+};
 use std::{
+    collections::BTreeMap,
     fs, io,
     ops::Deref,
     os::unix::fs::PermissionsExt,
@@ -137,50 +143,77 @@ pub fn remove_dir_recursively_force<P: AsRef<Path>>(path: P) -> Result<(), io::E
     Ok(())
 }
 
-// TODO: decrease code duplicate
-pub async fn cp_dir_patch<P, Q>(
-    src: P,
-    dst: Q,
-    installed_paths: &mut Vec<PathBuf>,
-    tx: &mut SqlTransaction,
-) -> Result<(), CatError>
+/// Create a symlink at `dst`, the parent dirs are created if needed
+pub fn symlink_force<P, Q>(target: P, dst: Q) -> Result<(), io::Error>
 where
     P: AsRef<Path>,
     Q: AsRef<Path>,
 {
-    let walk = WalkDir::new(&src).into_iter().filter_entry(|e| {
-        // only install dirs at the top level
-        if e.depth() == 1 && !e.file_type().is_dir() {
-            false
-        } else {
-            true
-        }
-    });
-    if let Err(e) = fs::create_dir_all(&dst)
-        && e.kind() == io::ErrorKind::PermissionDenied
+    let dst = dst.as_ref();
+    if let Some(parent) = dst.parent()
+        && let Err(e) = fs::create_dir_all(parent)
     {
+        if e.kind() != io::ErrorKind::PermissionDenied {
+            return Err(e);
+        }
         // improve permission
-        add_permit(dst.as_ref().parent().unwrap(), 0o200)?;
-        fs::create_dir_all(&dst)?;
+        add_permit(parent.parent().unwrap_or(parent), 0o200)?;
+        fs::create_dir_all(parent)?;
+    }
+    if let Err(e) = std::os::unix::fs::symlink(&target, dst) {
+        match e.kind() {
+            io::ErrorKind::AlreadyExists => {
+                remove_file_force(dst)?;
+                std::os::unix::fs::symlink(&target, dst)?;
+            }
+            io::ErrorKind::PermissionDenied => {
+                add_permit(dst.parent().unwrap(), 0o200)?;
+                std::os::unix::fs::symlink(&target, dst)?;
+            }
+            _ => return Err(e),
+        }
     }
+    Ok(())
+}
+
+/// The relative path to reach `to` from the dir `from_dir`, both of them should be absolute
+pub fn relative_path<P, Q>(from_dir: P, to: Q) -> PathBuf
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let mut from = from_dir.as_ref().components().peekable();
+    let mut to = to.as_ref().components().peekable();
+    while let (Some(a), Some(b)) = (from.peek(), to.peek())
+        && a == b
+    {
+        from.next();
+        to.next();
+    }
+    let mut result = PathBuf::new();
+    for _ in from {
+        result.push("..");
+    }
+    result.extend(to);
+    result
+}
+
+/// Copy a whole keg to `dst`, symlinks are kept as symlinks.
+/// Every file and symlink created is recorded in `installed_paths`
+pub fn cp_keg<P, Q>(src: P, dst: Q, installed_paths: &mut Vec<PathBuf>) -> Result<(), io::Error>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let walk = WalkDir::new(&src);
     for entry in walk {
-        let entry = entry.map_err(|e| -> io::Error { e.into() })?;
-        let relative_path = entry
-            .path()
-            .strip_prefix(&src)
-            .map_err(|e| io::Error::other(e))?;
+        let entry = entry?;
+        let relative_path = entry.path().strip_prefix(&src).map_err(io::Error::other)?;
         let dst = dst.as_ref().join(relative_path);
-        if tx.is_path_exist(&dst).await? {
-            return Err(CatError::Pac(format!(
-                "file path conflict: {}",
-                dst.display()
-            )));
-        }
-        if !entry.file_type().is_dir() {
-            installed_paths.push(dst.to_path_buf());
+        if interrupt::is_interrupted() {
+            return Err(io::ErrorKind::Interrupted.into());
         }
         if entry.file_type().is_dir() {
-            // println!("create dir: {}", dst.display());
             if let Err(e) = fs::create_dir_all(&dst)
                 && e.kind() == io::ErrorKind::PermissionDenied
             {
@@ -190,9 +223,10 @@ where
             }
             continue;
         }
+        installed_paths.push(dst.to_path_buf());
         // fs::copy always follow the symlink, so we need to create symlink manually
         // NOTICE: most symlink is relative path
-        else if entry.file_type().is_symlink() {
+        if entry.file_type().is_symlink() {
             let target = fs::read_link(entry.path())?;
             if target.is_absolute() {
                 // It is too silly to use absolute path for symlink,
@@ -200,129 +234,215 @@ where
                 fs::copy(&target, &dst)?;
                 continue;
             }
-            // println!("create link {} -> {}", dst.display(), target.display());
-            if let Err(e) = std::os::unix::fs::symlink(&target, &dst) {
-                match e.kind() {
-                    io::ErrorKind::AlreadyExists => {
-                        remove_file_force(&dst)?;
-                        std::os::unix::fs::symlink(&target, &dst)?;
-                    }
-                    io::ErrorKind::PermissionDenied => {
-                        add_permit(&dst.parent().unwrap(), 0o200)?;
-                        std::os::unix::fs::symlink(&target, &dst)?;
-                    }
-                    _ => return Err(e.into()),
-                }
+            symlink_force(&target, &dst)?;
+            if !fs::exists(&dst)? {
+                eprintln!(
+                    "Warning: broken symlink: {} -> {}",
+                    dst.display(),
+                    target.display()
+                );
             }
-            continue;
-        }
-        // println!("copy {} -> {}", entry.path().display(), dst.display());
-        else {
+        } else {
             copy_file_force(entry.path(), &dst)?;
         }
     }
+    Ok(())
+}
 
+/// Plan a link in the prefix, it is checked for conflicts and recorded in `links`,
+/// but only created by `publish_links`
+pub async fn plan_link<P, Q>(
+    dst: P,
+    target: Q,
+    links: &mut BTreeMap<PathBuf, PathBuf>,
+    tx: &mut SqlTransaction,
+) -> Result<(), CatError>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let dst = dst.as_ref();
+    if tx.is_path_exist(dst).await? || links.contains_key(dst) {
+        return Err(CatError::Pac(format!(
+            "file path conflict: {}",
+            dst.display()
+        )));
+    }
+    links.insert(dst.to_path_buf(), target.as_ref().to_path_buf());
     Ok(())
 }
 
-// FIXME: the logic of handling error of permission denied is weird,
-// needs to be improved
-pub async fn cp_dir_with_record_and_check<P, Q>(
+/// Refuse planned links over files that exist but are not recorded, files not
+/// made by pac are only replaced with `overwrite`, and put back on rollback
+pub fn check_existing(links: &BTreeMap<PathBuf, PathBuf>, overwrite: bool) -> Result<(), CatError> {
+    let existing = links
+        .keys()
+        .filter(|dst| fs::symlink_metadata(dst).is_ok())
+        .map(|dst| dst.display().to_string())
+        .collect::<Vec<_>>();
+    if existing.is_empty() {
+        return Ok(());
+    }
+    if overwrite {
+        eprintln!(
+            "Warning: these files will be overwritten:\n  {}",
+            existing.join("\n  ")
+        );
+        return Ok(());
+    }
+    Err(CatError::Pac(format!(
+        "file path conflict, these files already exist, please remove them first\n\
+        or use `--overwrite` to replace them:\n  {}",
+        existing.join("\n  ")
+    )))
+}
+
+/// Plan links in `dst` for every file in `src`, pointing to the same file in `target_dir`.
+/// `src` can be a staged copy of `target_dir`. Directories are created instead of linked,
+/// so different packages can share them
+pub async fn link_dir<P, Q, R>(
     src: P,
     dst: Q,
-    installed_paths: &mut Vec<PathBuf>,
+    target_dir: R,
+    links: &mut BTreeMap<PathBuf, PathBuf>,
     tx: &mut SqlTransaction,
-) -> Result<Vec<(PathBuf, PathBuf)>, CatError>
+) -> Result<(), CatError>
 where
     P: AsRef<Path>,
     Q: AsRef<Path>,
+    R: AsRef<Path>,
 {
-    let walk = WalkDir::new(&src).into_iter().filter_entry(|e| {
-        // only install specific dirs at the top level
-        if e.depth() == 1
-            && (!DIR_TO_INSTALL.contains(e.file_name().to_string_lossy().as_ref())
-                || !e.file_type().is_dir())
-        {
-            false
-        } else {
-            true
-        }
-    });
-    let mut symlinks = Vec::new();
-    if let Err(e) = fs::create_dir_all(&dst)
-        && e.kind() == io::ErrorKind::PermissionDenied
-    {
-        // improve permission
-        add_permit(dst.as_ref().parent().unwrap(), 0o200)?;
-        fs::create_dir_all(&dst)?;
-    }
+    let walk = WalkDir::new(&src);
     for entry in walk {
         let entry = entry.map_err(|e| -> io::Error { e.into() })?;
-        let mut relative_path = entry
-            .path()
-            .strip_prefix(&src)
-            .map_err(|e| io::Error::other(e))?;
-        if relative_path.starts_with(".bottle") {
-            relative_path = relative_path
-                .strip_prefix(".bottle")
-                .map_err(|e| io::Error::other(e))?;
-        }
-        let dst = dst.as_ref().join(relative_path);
-        if tx.is_path_exist(&dst).await? {
-            return Err(CatError::Pac(format!(
-                "file path conflict: {}",
-                dst.display()
-            )));
-        }
-        if !entry.file_type().is_dir() {
-            installed_paths.push(dst.to_path_buf());
-        }
         if entry.file_type().is_dir() {
-            // println!("create dir: {}", dst.display());
-            if let Err(e) = fs::create_dir_all(&dst)
-                && e.kind() == io::ErrorKind::PermissionDenied
-            {
-                // improve permission
-                add_permit(dst.parent().unwrap(), 0o200)?;
-                fs::create_dir_all(&dst)?;
-            }
             continue;
         }
-        // fs::copy always follow the symlink, so we need to create symlink manually
-        // NOTICE: most symlink is relative path
-        else if entry.file_type().is_symlink() {
-            let target = fs::read_link(entry.path())?;
-            if target.is_absolute() {
-                // It is too silly to use absolute path for symlink,
-                // anyway, let's copy them directly
-                fs::copy(&target, &dst)?;
-                continue;
+        interrupt::check()?;
+        let relative_path = entry.path().strip_prefix(&src).map_err(io::Error::other)?;
+        plan_link(
+            dst.as_ref().join(relative_path),
+            target_dir.as_ref().join(relative_path),
+            links,
+            tx,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Create planned links with relative symlinks. Each one is made in `scratch` and
+/// renamed into place, so a link in the prefix is never half made. A file it replaces
+/// is moved into `scratch` and recorded in `replaced`, to be restored on rollback.
+/// Links in place are recorded in `created`, only they are removed on rollback
+pub fn publish_links<P>(
+    links: &BTreeMap<PathBuf, PathBuf>,
+    scratch: P,
+    replaced: &mut Vec<(PathBuf, PathBuf)>,
+    created: &mut Vec<PathBuf>,
+) -> Result<(), CatError>
+where
+    P: AsRef<Path>,
+{
+    let new_dir = scratch.as_ref().join("new");
+    let backup_dir = scratch.as_ref().join("backup");
+    fs::create_dir_all(&new_dir)?;
+    for (i, (dst, target)) in links.iter().enumerate() {
+        journal::record(&Entry::Create { path: dst.clone() })?;
+        let relative = relative_path(dst.parent().unwrap(), target);
+        let made = new_dir.join(i.to_string());
+        std::os::unix::fs::symlink(&relative, &made)?;
+        if let Some(parent) = dst.parent()
+            && let Err(e) = fs::create_dir_all(parent)
+        {
+            if e.kind() != io::ErrorKind::PermissionDenied {
+                return Err(e.into());
             }
-            // println!("create link {} -> {}", dst.display(), target.display());
-            if let Err(e) = std::os::unix::fs::symlink(&target, &dst) {
-                match e.kind() {
-                    io::ErrorKind::AlreadyExists => {
-                        remove_file_force(&dst)?;
-                        std::os::unix::fs::symlink(&target, &dst)?;
-                    }
-                    io::ErrorKind::PermissionDenied => {
-                        add_permit(&dst.parent().unwrap(), 0o200)?;
-                        std::os::unix::fs::symlink(&target, &dst)?;
-                    }
-                    _ => return Err(e.into()),
-                }
+            // improve permission
+            add_permit(parent.parent().unwrap_or(parent), 0o200)?;
+            fs::create_dir_all(parent)?;
+        }
+        backup_file(dst, &backup_dir, replaced)?;
+        if let Err(e) = fs::rename(&made, dst) {
+            if e.kind() != io::ErrorKind::PermissionDenied {
+                return Err(e.into());
             }
-            // convert target to an absolute path
-            symlinks.push((dst, entry.path().parent().unwrap().join(target)));
-            continue;
+            add_permit(dst.parent().unwrap(), 0o200)?;
+            fs::rename(&made, dst)?;
         }
-        // println!("copy {} -> {}", entry.path().display(), dst.display());
-        else {
-            copy_file_force(entry.path(), &dst)?;
+        created.push(dst.clone());
+    }
+    remove_dir_force(new_dir)?;
+    Ok(())
+}
+
+/// Move an existing file out of the way, keeping its path under `backup_dir`
+fn backup_file(
+    path: &Path,
+    backup_dir: &Path,
+    replaced: &mut Vec<(PathBuf, PathBuf)>,
+) -> Result<(), CatError> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if !meta.is_dir() => {}
+        _ => return Ok(()),
+    }
+    let backup = backup_dir.join(path.strip_prefix("/").unwrap_or(path));
+    // never replace the backup of another file
+    if fs::symlink_metadata(&backup).is_ok() {
+        return Err(CatError::Pac(format!(
+            "backup already exists: {}",
+            backup.display()
+        )));
+    }
+    journal::record(&Entry::Backup {
+        path: path.to_path_buf(),
+        backup: backup.clone(),
+    })?;
+    fs::create_dir_all(backup.parent().unwrap())?;
+    fs::rename(path, &backup)?;
+    replaced.push((path.to_path_buf(), backup));
+    Ok(())
+}
+
+/// Put back the files replaced by `publish_links`, the links over them should be removed first.
+/// Every file is tried, the ones that can not be restored are reported together
+pub fn restore_backups(replaced: &[(PathBuf, PathBuf)]) -> Result<(), CatError> {
+    let mut failed = Vec::new();
+    for (path, backup) in replaced.iter().rev() {
+        let restored = match path.parent() {
+            Some(parent) => fs::create_dir_all(parent),
+            None => Ok(()),
         }
+        .and_then(|_| fs::rename(backup, path));
+        if let Err(e) = restored {
+            failed.push(format!("{} from {}: {e}", path.display(), backup.display()));
+        }
+    }
+    if !failed.is_empty() {
+        return Err(CatError::Pac(format!(
+            "can not restore replaced files:\n  {}",
+            failed.join("\n  ")
+        )));
     }
+    Ok(())
+}
 
-    Ok(symlinks)
+/// Undo `publish_links`, the created links are removed and the replaced files put back
+pub fn unpublish_links(
+    created: &[PathBuf],
+    replaced: &[(PathBuf, PathBuf)],
+) -> Result<(), CatError> {
+    for p in created {
+        if let Err(e) = remove_file_force(p)
+            && e.kind() != io::ErrorKind::NotFound
+        {
+            eprintln!(
+                "Warning: Can not remove created link: {}, error: {e}",
+                p.display()
+            );
+        }
+    }
+    restore_backups(replaced)
 }
 
 pub fn cp_dir_with_record<P, Q>(
@@ -402,3 +522,86 @@ where
 
     Ok(())
 }
+
+#[test]
+fn test_relative_path() {
+    assert_eq!(
+        relative_path("/opt/pac/bin", "/opt/pac/Cellar/fish/4.1.2/bin/fish"),
+        Path::new("../Cellar/fish/4.1.2/bin/fish")
+    );
+    assert_eq!(
+        relative_path("/opt/pac/opt", "/opt/pac/Cellar/fish/4.1.2"),
+        Path::new("../Cellar/fish/4.1.2")
+    );
+    assert_eq!(
+        relative_path(
+            "/opt/pac/etc/openssl@3",
+            "/opt/pac/etc/ca-certificates/cacert.pem"
+        ),
+        Path::new("../ca-certificates/cacert.pem")
+    );
+}
+
+#[tokio::test]
+async fn test_plan_link() {
+    let mut tx = SqlTransaction::memory().await.unwrap();
+    let root = std::env::temp_dir().join(format!("pac-test-plan-link-{}", std::process::id()));
+    let keg = root.join("Cellar/fish/4.1.2");
+    let mut links = BTreeMap::new();
+    plan_link(
+        root.join("bin/fish"),
+        keg.join("bin/fish"),
+        &mut links,
+        &mut tx,
+    )
+    .await
+    .unwrap();
+    // two files of a keg linked to the same place
+    let Err(CatError::Pac(e)) = plan_link(
+        root.join("bin/fish"),
+        keg.join("libexec/fish"),
+        &mut links,
+        &mut tx,
+    )
+    .await
+    else {
+        panic!("expected a file path conflict");
+    };
+    assert!(e.starts_with("file path conflict"));
+    assert_eq!(links[&root.join("bin/fish")], keg.join("bin/fish"));
+    check_existing(&links, false).unwrap();
+    // a file not made by pac
+    fs::create_dir_all(root.join("bin")).unwrap();
+    fs::write(root.join("bin/fish"), "").unwrap();
+    assert!(check_existing(&links, false).is_err());
+    check_existing(&links, true).unwrap();
+    remove_dir_recursively_force(&root).unwrap();
+}
+
+#[test]
+fn test_unpublish_links() {
+    let root = std::env::temp_dir().join(format!("pac-test-publish-{}", std::process::id()));
+    let keg = root.join("Cellar/fish/4.1.2");
+    fs::create_dir_all(root.join("bin")).unwrap();
+    fs::write(root.join("bin/fish"), "not made by pac").unwrap();
+    let links = BTreeMap::from([
+        (root.join("bin/fish"), keg.join("bin/fish")),
+        (root.join("bin/fish_indent"), keg.join("bin/fish_indent")),
+    ]);
+    let (mut replaced, mut created) = (Vec::new(), Vec::new());
+    publish_links(&links, root.join("scratch"), &mut replaced, &mut created).unwrap();
+    assert_eq!(
+        fs::read_link(root.join("bin/fish")).unwrap(),
+        Path::new("../Cellar/fish/4.1.2/bin/fish")
+    );
+    assert_eq!(replaced.len(), 1);
+    assert_eq!(created.len(), 2);
+
+    unpublish_links(&created, &replaced).unwrap();
+    assert_eq!(
+        fs::read_to_string(root.join("bin/fish")).unwrap(),
+        "not made by pac"
+    );
+    assert!(fs::symlink_metadata(root.join("bin/fish_indent")).is_err());
+    remove_dir_recursively_force(&root).unwrap();
+}
@@ -0,0 +1,174 @@
+//! Minimal Mach-O files for tests. Every slice is one page, the space left
+//! after the load commands is header pad
+
+pub const ARM64: u32 = 0x0100000C;
+pub const X86_64: u32 = 0x01000007;
+
+const PAGE: usize = 4096;
+
+fn name(s: &str) -> [u8; 16] {
+    let mut buf = [0; 16];
+    buf[..s.len()].copy_from_slice(s.as_bytes());
+    buf
+}
+
+/// A load command holding a string after `header`, padded to 8 bytes
+fn command(cmd: u32, header: &[u32], s: &str) -> Vec<u8> {
+    let offset = 8 + header.len() * 4;
+    let size = (offset + s.len() + 1).div_ceil(8) * 8;
+    let mut buf = Vec::with_capacity(size);
+    buf.extend(cmd.to_le_bytes());
+    buf.extend((size as u32).to_le_bytes());
+    for v in header {
+        buf.extend(v.to_le_bytes());
+    }
+    buf.extend(s.as_bytes());
+    buf.resize(size, 0);
+    buf
+}
+
+/// `LC_SEGMENT_64` with the given sections, as (name, file offset, size)
+fn segment(segname: &str, fileoff: u64, filesize: u64, sections: &[(&str, u32, usize)]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend(0x19_u32.to_le_bytes());
+    buf.extend((72 + 80 * sections.len() as u32).to_le_bytes());
+    buf.extend(name(segname));
+    // vmaddr, vmsize, fileoff, filesize
+    for v in [fileoff, filesize, fileoff, filesize] {
+        buf.extend(v.to_le_bytes());
+    }
+    // maxprot, initprot, nsects, flags
+    for v in [5, 5, sections.len() as u32, 0] {
+        buf.extend(v.to_le_bytes());
+    }
+    for (sectname, offset, size) in sections {
+        buf.extend(name(sectname));
+        buf.extend(name(segname));
+        buf.extend((*offset as u64).to_le_bytes());
+        buf.extend((*size as u64).to_le_bytes());
+        // offset, align, reloff, nreloc, flags, reserved1, reserved2, reserved3
+        for v in [*offset, 0, 0, 0, 0, 0, 0, 0] {
+            buf.extend(v.to_le_bytes());
+        }
+    }
+    buf
+}
+
+pub struct Fixture {
+    cputype: u32,
+    filetype: u32,
+    commands: Vec<Vec<u8>>,
+    /// sections of `__TEXT`, as (name, file offset, data)
+    sections: Vec<(&'static str, u32, Vec<u8>)>,
+    linkedit: bool,
+}
+
+impl Fixture {
+    pub fn executable(cputype: u32) -> Self {
+        Self {
+            cputype,
+            filetype: 2,
+            commands: Vec::new(),
+            sections: Vec::new(),
+            linkedit: false,
+        }
+    }
+
+    pub fn dylib(cputype: u32, id: &str) -> Self {
+        let mut fixture = Self::executable(cputype);
+        fixture.filetype = 6;
+        fixture
+            .commands
+            .push(command(0xd, &[24, 2, 0x10000, 0x10000], id));
+        fixture
+    }
+
+    pub fn load_dylib(mut self, lib: &str) -> Self {
+        // name offset, timestamp, current version, compatibility version
+        self.commands
+            .push(command(0xc, &[24, 2, 0x10000, 0x10000], lib));
+        self
+    }
+
+    pub fn rpath(mut self, rpath: &str) -> Self {
+        self.commands.push(command(0x8000001c, &[12], rpath));
+        self
+    }
+
+    /// A section of `__TEXT` at `offset` in the page
+    pub fn section(mut self, sectname: &'static str, offset: u32, data: &[u8]) -> Self {
+        self.sections.push((sectname, offset, data.to_vec()));
+        self
+    }
+
+    /// An empty `__LINKEDIT`, where a code signature goes
+    pub fn linkedit(mut self) -> Self {
+        self.linkedit = true;
+        self
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let mut cmds = Vec::new();
+        if !self.sections.is_empty() {
+            let sections = self
+                .sections
+                .iter()
+                .map(|(sectname, offset, data)| (*sectname, *offset, data.len()))
+                .collect::<Vec<_>>();
+            cmds.push(segment("__TEXT", 0, PAGE as u64, &sections));
+        }
+        cmds.extend(self.commands.iter().cloned());
+        if self.linkedit {
+            cmds.push(segment("__LINKEDIT", PAGE as u64, 0, &[]));
+        }
+        let sizeofcmds = cmds.iter().map(Vec::len).sum::<usize>() as u32;
+        let mut buf = Vec::new();
+        // magic, cputype, cpusubtype, filetype, ncmds, sizeofcmds, flags, reserved
+        for v in [
+            0xfeedfacf,
+            self.cputype,
+            0,
+            self.filetype,
+            cmds.len() as u32,
+            sizeofcmds,
+            0,
+            0,
+        ] {
+            buf.extend(u32::to_le_bytes(v));
+        }
+        buf.extend(cmds.concat());
+        buf.resize(PAGE, 0);
+        for (_, offset, data) in self.sections.iter() {
+            let offset = *offset as usize;
+            buf[offset..offset + data.len()].copy_from_slice(data);
+        }
+        buf
+    }
+}
+
+/// Put slices into a fat binary, the slice `i` starts at page `i + 1`
+pub fn fat(slices: &[Vec<u8>]) -> Vec<u8> {
+    let mut fat = Vec::new();
+    fat.extend(0xcafebabe_u32.to_be_bytes());
+    fat.extend((slices.len() as u32).to_be_bytes());
+    let mut offset = PAGE;
+    for slice in slices {
+        // cputype, cpusubtype, offset, size, align
+        for v in [
+            u32::from_le_bytes(slice[4..8].try_into().unwrap()),
+            0,
+            offset as u32,
+            slice.len() as u32,
+            12,
+        ] {
+            fat.extend(v.to_be_bytes());
+        }
+        offset += slice.len().div_ceil(PAGE) * PAGE;
+    }
+    fat.resize(PAGE, 0);
+    for slice in slices {
+        fat.extend(slice);
+        fat.resize(fat.len().div_ceil(PAGE) * PAGE, 0);
+    }
+    fat
+}
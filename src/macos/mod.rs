@@ -1,2 +1,4 @@
 pub mod file;
+#[cfg(test)]
+pub mod fixture;
 pub mod version;
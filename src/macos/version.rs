@@ -1,9 +1,9 @@
 use std::sync::LazyLock;
 
 use objc2_foundation::NSProcessInfo;
-use strum::{AsRefStr, Display, FromRepr};
+use strum::{AsRefStr, Display, EnumIter, EnumString, FromRepr};
 
-pub static ARCH_OS: LazyLock<String> = LazyLock::new(|| match get_version_and_arch() {
+pub static MACOS_VERSION: LazyLock<MacOSVersion> = LazyLock::new(|| match get_version() {
     Ok(v) => v,
     Err(e) => {
         eprintln!("{e}");
@@ -11,28 +11,50 @@ pub static ARCH_OS: LazyLock<String> = LazyLock::new(|| match get_version_and_ar
     }
 });
 
-pub fn get_version_and_arch() -> Result<String, &'static str> {
+pub static ARCH_OS: LazyLock<String> = LazyLock::new(|| bottle_tag(ARCH, *MACOS_VERSION));
+
+/// Homebrew names arm64 bottles like `arm64_sonoma`, and x86_64 bottles just `sonoma`
+pub fn bottle_tag(arch: &str, os: MacOSVersion) -> String {
+    if arch == "x86_64" {
+        os.as_ref().to_string()
+    } else {
+        format!("{}_{}", arch, os.as_ref())
+    }
+}
+
+pub fn get_version() -> Result<MacOSVersion, &'static str> {
     let process_info = NSProcessInfo::processInfo();
     let ver = process_info.operatingSystemVersion();
     let major_ver = ver.majorVersion;
     if major_ver < 0 {
         return Err("Failed to get macOS version");
     }
-    let version = MacOSVersion::from_repr(major_ver as usize).ok_or("Unsupported macOS version")?;
-
     // println!("macOS version: macOS {} {}", version, ver.majorVersion);
-    Ok(format!(
-        "{}_{}",
-        ARCH,
-        version.to_string().to_ascii_lowercase()
-    ))
+    MacOSVersion::from_repr(major_ver as usize).ok_or("Unsupported macOS version")
 }
+
 #[cfg(target_arch = "aarch64")]
 pub const ARCH: &str = "arm64";
 #[cfg(target_arch = "x86_64")]
 pub const ARCH: &str = "x86_64";
 
-#[derive(Debug, AsRefStr, FromRepr, Display)]
+/// Supported macOS releases, ordered from oldest to newest.
+/// The string form matches the names used by Homebrew, such as `big_sur`
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    AsRefStr,
+    FromRepr,
+    Display,
+    EnumString,
+    EnumIter,
+)]
+#[strum(serialize_all = "snake_case")]
 pub enum MacOSVersion {
     Catalina = 10,
     BigSur,
@@ -3,31 +3,86 @@ use std::sync::LazyLock;
 
 use clap::Parser;
 use pac::cli::command::{Cli, Commands};
+use pac::package::audit::audit_pac;
+use pac::package::info::show_info;
+use pac::package::journal::{repair, unfinished};
+use pac::package::link::{link_pac, unlink_pac};
+use pac::package::linkage::check_linkage;
 use pac::package::list::list_pacs;
+use pac::package::outdated::list_outdated;
 use pac::{
-    CACHE_DIR, brew_api::install_pac, database::local::init_db, macos::version::ARCH_OS,
+    CACHE_DIR,
+    brew_api::{InstallOptions, install_pac},
+    config::init_config,
+    database::local::init_db,
+    interrupt::{self, EXIT_INTERRUPTED},
+    macos::version::ARCH_OS,
     package::uninstall::uninstall_a_pac,
 };
 
 #[tokio::main]
 async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    // only commands that roll back listen, Ctrl-C stops the others at once
+    if matches!(
+        cli.command,
+        Commands::Install { .. } | Commands::Uninstall { .. } | Commands::Link { .. }
+    ) {
+        interrupt::listen();
+    }
     LazyLock::force(&ARCH_OS);
     LazyLock::force(&CACHE_DIR);
+    if let Err(e) = init_config(cli.root) {
+        eprintln!("Can not load config: {e}");
+        return ExitCode::FAILURE;
+    }
     if let Err(e) = init_db().await {
         eprintln!("Can not initialize database: {e}");
         return ExitCode::FAILURE;
     }
-    let cli = Cli::parse();
+    match unfinished() {
+        Ok(journals) if !journals.is_empty() && !matches!(cli.command, Commands::Repair) => {
+            eprintln!(
+                "{} operations were interrupted, run `pac repair` to clean up",
+                journals.len()
+            );
+            if matches!(
+                cli.command,
+                Commands::Install { .. }
+                    | Commands::Uninstall { .. }
+                    | Commands::Link { .. }
+                    | Commands::Unlink { .. }
+            ) {
+                return ExitCode::FAILURE;
+            }
+        }
+        Ok(_) => {}
+        Err(e) => eprintln!("Warning: can not check interrupted operations: {e}"),
+    }
     match cli.command {
-        Commands::Install { name } => {
+        Commands::Install {
+            name,
+            force,
+            force_bottle_deps,
+            no_bottle_fallback,
+            strict,
+            overwrite,
+        } => {
             println!("Installing {}\n", name);
-            if let Err(e) = install_pac(&name).await {
+            let options = InstallOptions {
+                force,
+                force_bottle_deps,
+                no_bottle_fallback,
+                strict,
+                overwrite,
+            };
+            if let Err(e) = install_pac(&name, options).await {
                 eprintln!("\nCan not install {name}, error:\n{e}");
             }
         }
-        Commands::Uninstall { name } => {
+        Commands::Uninstall { name, force } => {
             println!("Uninstalling {}\n", name);
-            if let Err(e) = uninstall_a_pac(&name).await {
+            if let Err(e) = uninstall_a_pac(&name, force).await {
                 eprintln!("\nCan not finish, encounter an error:\n{e}");
             }
         }
@@ -36,9 +91,48 @@ async fn main() -> ExitCode {
                 eprintln!("\nCan not list installed packages, error:\n{e}");
             }
         }
-        _ => {
-            println!("Command not implemented yet.");
+        Commands::Link {
+            name,
+            force,
+            overwrite,
+        } => {
+            if let Err(e) = link_pac(&name, force, overwrite).await {
+                eprintln!("\nCan not link {name}, error:\n{e}");
+            }
+        }
+        Commands::Unlink { name } => {
+            if let Err(e) = unlink_pac(&name).await {
+                eprintln!("\nCan not unlink {name}, error:\n{e}");
+            }
+        }
+        Commands::Audit { name } => {
+            if let Err(e) = audit_pac(&name).await {
+                eprintln!("\nAudit of {name} failed:\n{e}");
+            }
+        }
+        Commands::Linkage { name } => {
+            if let Err(e) = check_linkage(name.as_deref()).await {
+                eprintln!("\nLinkage check failed:\n{e}");
+            }
+        }
+        Commands::Repair => {
+            if let Err(e) = repair().await {
+                eprintln!("\nCan not repair, error:\n{e}");
+            }
         }
+        Commands::Info { name } => {
+            if let Err(e) = show_info(&name).await {
+                eprintln!("\nCan not show info of {name}, error:\n{e}");
+            }
+        }
+        Commands::Outdated => {
+            if let Err(e) = list_outdated().await {
+                eprintln!("\nCan not check outdated packages, error:\n{e}");
+            }
+        }
+    }
+    if interrupt::is_interrupted() {
+        return ExitCode::from(EXIT_INTERRUPTED);
     }
     ExitCode::SUCCESS
 }
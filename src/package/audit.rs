@@ -0,0 +1,200 @@
+use std::{
+    fmt, fs, io,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::LazyLock,
+};
+
+use aho_corasick::AhoCorasick;
+use goblin::mach::{Mach, MachO};
+use rayon::prelude::*;
+use walkdir::WalkDir;
+
+use crate::{database::local::SqlTransaction, errors::CatError, package::load_path::describe_arch};
+
+/// Paths that should never survive relocation
+const LEFTOVER_PATTERNS: [&str; 3] = ["/opt/homebrew", "@@HOMEBREW_", "/usr/local/Cellar"];
+
+static LEFTOVER_AC: LazyLock<AhoCorasick> =
+    LazyLock::new(|| AhoCorasick::new(LEFTOVER_PATTERNS).expect("patterns are valid"));
+
+/// A Homebrew path left in a file after relocation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Leftover {
+    pub file: PathBuf,
+    pub offset: usize,
+    /// where it is, such as `arm64, load commands`, `arm64, __TEXT,__cstring` or `text`
+    pub region: String,
+    pub text: String,
+}
+
+impl fmt::Display for Leftover {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{:#x} [{}] {}",
+            self.file.display(),
+            self.offset,
+            self.region,
+            self.text
+        )
+    }
+}
+
+/// Regions of every slice of a Mach-O, so a leftover can be told apart
+/// between load commands and sections
+fn macho_regions(data: &[u8]) -> Vec<(Range<usize>, String)> {
+    fn slice_regions(macho: &MachO, base: usize, regions: &mut Vec<(Range<usize>, String)>) {
+        let arch = describe_arch(macho.header.cputype);
+        let header_size = if macho.is_64 { 32 } else { 28 };
+        let end = base + header_size + macho.header.sizeofcmds as usize;
+        regions.push((base..end, format!("{arch}, load commands")));
+        for segment in macho.segments.iter() {
+            let Ok(sections) = segment.sections() else {
+                continue;
+            };
+            for (section, _) in sections {
+                if section.offset == 0 {
+                    continue;
+                }
+                let start = base + section.offset as usize;
+                let name = format!(
+                    "{arch}, {},{}",
+                    section.segname().unwrap_or("?"),
+                    section.name().unwrap_or("?")
+                );
+                regions.push((start..start + section.size as usize, name));
+            }
+        }
+    }
+    let mut regions = Vec::new();
+    match Mach::parse(data) {
+        Ok(Mach::Binary(macho)) => slice_regions(&macho, 0, &mut regions),
+        Ok(Mach::Fat(fat)) => {
+            let Ok(arches) = fat.arches() else {
+                return regions;
+            };
+            for arch in arches {
+                if let Ok(macho) = MachO::parse(arch.slice(data), 0) {
+                    slice_regions(&macho, arch.offset as usize, &mut regions);
+                }
+            }
+        }
+        Err(_) => {}
+    }
+    regions
+}
+
+/// Find the Homebrew paths left in the content of a file
+pub fn audit_data(file: &Path, data: &[u8]) -> Vec<Leftover> {
+    let mut matches = LEFTOVER_AC.find_iter(data).peekable();
+    if matches.peek().is_none() {
+        return Vec::new();
+    }
+    let regions = if infer::is(data, "mach") {
+        macho_regions(data)
+    } else {
+        Vec::new()
+    };
+    let default_region = if regions.is_empty() && memchr::memchr(0, data).is_none() {
+        "text"
+    } else {
+        "binary"
+    };
+    matches
+        .map(|m| {
+            let offset = m.start();
+            let end = data[offset..]
+                .iter()
+                .position(|b| matches!(b, 0 | b'\n' | b'"' | b'\'' | b' ' | b'\t'))
+                .map_or(data.len(), |len| offset + len)
+                .min(offset + 200);
+            let region = regions
+                .iter()
+                .find(|(range, _)| range.contains(&offset))
+                .map_or(default_region, |(_, name)| name.as_str());
+            Leftover {
+                file: file.to_path_buf(),
+                offset,
+                region: region.to_string(),
+                text: String::from_utf8_lossy(&data[offset..end]).into_owned(),
+            }
+        })
+        .collect()
+}
+
+/// Audit regular files, symlinks and missing files are skipped
+pub fn audit_files<P>(files: &[P]) -> Result<Vec<Leftover>, CatError>
+where
+    P: AsRef<Path> + Sync,
+{
+    let leftovers = files
+        .par_iter()
+        .map(|file| {
+            let file = file.as_ref();
+            match fs::symlink_metadata(file) {
+                Ok(meta) if meta.is_file() => Ok(audit_data(file, &fs::read(file)?)),
+                _ => Ok(Vec::new()),
+            }
+        })
+        .collect::<Result<Vec<_>, CatError>>()?;
+    Ok(leftovers.into_iter().flatten().collect())
+}
+
+/// Audit the files staged for installation
+pub fn audit_dir<P>(dir: P) -> Result<Vec<Leftover>, CatError>
+where
+    P: AsRef<Path>,
+{
+    let mut files = Vec::new();
+    for entry in WalkDir::new(dir) {
+        let entry = entry.map_err(io::Error::from)?;
+        if entry.file_type().is_file() {
+            files.push(entry.into_path());
+        }
+    }
+    audit_files(&files)
+}
+
+pub fn print_leftovers(leftovers: &[Leftover]) {
+    for leftover in leftovers {
+        eprintln!("  {leftover}");
+    }
+}
+
+/// `pac audit`, check the installed files of a package
+pub async fn audit_pac(name: &str) -> Result<(), CatError> {
+    let mut tx = SqlTransaction::new().await?;
+    let Some((id, _)) = tx.is_installed(name).await? else {
+        return Err(CatError::Pac(format!("Package {} is not installed", name)));
+    };
+    let files = tx.get_installed_files(id).await?;
+    println!("auditing {} files of {}...", files.len(), name);
+    let leftovers = audit_files(&files)?;
+    if leftovers.is_empty() {
+        println!("No Homebrew paths are left in {}", name);
+        return Ok(());
+    }
+    eprintln!("Homebrew paths left in {}:", name);
+    print_leftovers(&leftovers);
+    Err(CatError::Pac(format!(
+        "{} Homebrew paths are left in {}",
+        leftovers.len(),
+        name
+    )))
+}
+
+#[test]
+fn test_audit_data() {
+    let data = b"prefix=/opt/pac\nlibdir=/usr/local/Cellar/foo/1.0/lib\nx=\"@@HOMEBREW_PERL@@\"";
+    let leftovers = audit_data(Path::new("foo.pc"), data);
+    let report = leftovers.iter().map(|l| l.to_string()).collect::<Vec<_>>();
+    assert_eq!(
+        report,
+        [
+            "foo.pc:0x17 [text] /usr/local/Cellar/foo/1.0/lib",
+            "foo.pc:0x38 [text] @@HOMEBREW_PERL@@",
+        ]
+    );
+    assert!(audit_data(Path::new("foo.pc"), b"prefix=/opt/pac").is_empty());
+}
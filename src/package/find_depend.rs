@@ -5,14 +5,87 @@ use std::{
 };
 
 use crate::{
-    brew_api::{PacInfo, get_json_api, get_json_api_multi},
+    brew_api::{PacInfo, UsesFromMacos, get_json_api, get_json_api_multi},
     database::local::SqlTransaction,
     errors::CatError,
+    macos::version::MacOSVersion,
 };
 
+/// How dependencies listed in `uses_from_macos` are handled
+#[derive(Debug, Clone, Copy)]
+pub struct DependPolicy {
+    pub os: MacOSVersion,
+    /// install Homebrew's copies of dependencies provided by macOS
+    pub force_bottle_deps: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OsDepDecision {
+    /// provided by macOS, skip it
+    System,
+    /// macOS provides it since a newer release, install the bottle
+    NewerThanOs(MacOSVersion),
+    /// `--force-bottle-deps` is set, install the bottle
+    Forced,
+}
+
+#[derive(Debug, Clone)]
+pub struct OsDep {
+    pub name: String,
+    pub decision: OsDepDecision,
+}
+
+impl DependPolicy {
+    /// Decide for every runtime dependency in `uses_from_macos`,
+    /// build and test dependencies are ignored since we only install bottles
+    pub fn macos_deps(&self, pac: &PacInfo) -> Vec<OsDep> {
+        pac.uses_from_macos
+            .iter()
+            .enumerate()
+            .filter_map(|(i, dep)| {
+                let UsesFromMacos::Runtime(name) = dep else {
+                    return None;
+                };
+                let since = pac
+                    .uses_from_macos_bounds
+                    .get(i)
+                    .and_then(|b| b.since.as_deref());
+                let decision = if self.force_bottle_deps {
+                    OsDepDecision::Forced
+                } else {
+                    match since.and_then(|s| s.parse::<MacOSVersion>().ok()) {
+                        Some(since) if since > self.os => OsDepDecision::NewerThanOs(since),
+                        // unknown bounds are releases older than the ones we support
+                        _ => OsDepDecision::System,
+                    }
+                };
+                Some(OsDep {
+                    name: name.clone(),
+                    decision,
+                })
+            })
+            .collect()
+    }
+
+    /// Dependencies that should be installed together with the package
+    pub fn runtime_deps(&self, pac: &PacInfo) -> Vec<String> {
+        let mut deps = pac.dependencies.clone();
+        deps.extend(
+            self.macos_deps(pac)
+                .into_iter()
+                .filter(|d| d.decision != OsDepDecision::System)
+                .map(|d| d.name),
+        );
+        deps
+    }
+}
+
 /// In the future we’ll switch to a database, so dependency parsing and database updates
 /// will become two separate operations — updating the database won’t always be required.
-pub async fn resolve_depend(root: PacInfo) -> Result<Vec<Rc<PacInfo>>, CatError> {
+pub async fn resolve_depend(
+    root: PacInfo,
+    policy: &DependPolicy,
+) -> Result<Vec<Rc<PacInfo>>, CatError> {
     let mut cache: HashMap<Rc<String>, Rc<PacInfo>> = HashMap::new();
     let root_rc = Rc::new(root);
     cache.insert(Rc::new(root_rc.name.clone()), Rc::clone(&root_rc));
@@ -56,7 +129,7 @@ pub async fn resolve_depend(root: PacInfo) -> Result<Vec<Rc<PacInfo>>, CatError>
                     let pac = get_json_api(&name).await?;
                     cache.insert(name.clone(), Rc::new(pac));
                 }
-                let deps = cache.get(&name).unwrap().dependencies.clone();
+                let deps = policy.runtime_deps(cache.get(&name).unwrap());
                 let deps_uncached = deps
                     .iter()
                     .filter(|s| !cache.contains_key(*s))
@@ -125,11 +198,78 @@ where
     Ok(())
 }
 
+/// If the formula was renamed upstream, move the installed records of its
+/// old name to the new one, so we keep tracking it
+pub async fn migrate_renamed(pac: &PacInfo, tx: &mut SqlTransaction) -> Result<(), CatError> {
+    if tx.is_installed(&pac.name).await?.is_some() {
+        return Ok(());
+    }
+    for old_name in pac.oldnames.iter() {
+        if tx.is_installed(old_name).await?.is_some() {
+            println!(
+                "{} was renamed to {}, migrating records",
+                old_name, pac.name
+            );
+            tx.rename_pac(old_name, &pac.name).await?;
+            break;
+        }
+    }
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_resolve_depend() {
     let pac = get_json_api("imagemagick").await.unwrap();
-    let res = resolve_depend(pac).await.unwrap();
+    let policy = DependPolicy {
+        os: MacOSVersion::Sequoia,
+        force_bottle_deps: false,
+    };
+    let res = resolve_depend(pac, &policy).await.unwrap();
     for i in res {
         println!("{}", i.full_name);
     }
 }
+
+#[test]
+fn test_macos_deps() {
+    let json = r#"{
+        "name": "foo", "full_name": "foo", "tap": "homebrew/core",
+        "versions": { "stable": "1.0", "bottle": true }, "bottle": null,
+        "dependencies": ["bar"], "conflicts_with": [], "versioned_formulae": [],
+        "revision": 0, "caveats": null,
+        "deprecation_date": null, "deprecation_reason": null,
+        "disable_date": null, "disable_reason": null,
+        "uses_from_macos": ["zlib", { "python": "build" }, "curl", "libxml2"],
+        "uses_from_macos_bounds": [{}, {}, { "since": "tahoe" }, { "since": "sierra" }]
+    }"#;
+    let pac: PacInfo = serde_json::from_str(json).unwrap();
+    let policy = DependPolicy {
+        os: MacOSVersion::Sequoia,
+        force_bottle_deps: false,
+    };
+    let decisions = policy
+        .macos_deps(&pac)
+        .into_iter()
+        .map(|d| (d.name, d.decision))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        decisions,
+        vec![
+            ("zlib".to_string(), OsDepDecision::System),
+            (
+                "curl".to_string(),
+                OsDepDecision::NewerThanOs(MacOSVersion::Tahoe)
+            ),
+            ("libxml2".to_string(), OsDepDecision::System),
+        ]
+    );
+    assert_eq!(policy.runtime_deps(&pac), vec!["bar", "curl"]);
+    let forced = DependPolicy {
+        force_bottle_deps: true,
+        ..policy
+    };
+    assert_eq!(
+        forced.runtime_deps(&pac),
+        vec!["bar", "zlib", "curl", "libxml2"]
+    );
+}
@@ -0,0 +1,54 @@
+use chrono::{Local, TimeZone};
+
+use crate::{
+    brew_api::resolve_json_api,
+    config::pac_root,
+    database::local::{PacState, SqlTransaction},
+    errors::CatError,
+};
+
+pub async fn show_info(name: &str) -> Result<(), CatError> {
+    let mut tx = SqlTransaction::new().await?;
+    if let Some(pac) = tx.get_pac_info(name).await? {
+        println!("{} {}", pac.name, pac.version);
+        println!("Arch: {}", pac.arch);
+        if let Some(tag) = pac.bottle_tag {
+            println!("Bottle: {}", tag);
+        }
+        let reason = if pac.explicit {
+            "explicitly"
+        } else {
+            "as a dependency"
+        };
+        let time = Local
+            .timestamp_opt(pac.install_time, 0)
+            .single()
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        println!("Installed {} at {}", reason, time);
+        if pac.keg_only {
+            println!("Keg-only, it is not linked into {} by default", pac_root());
+        }
+        if let PacState::Broken = pac.state {
+            println!("State: broken, please uninstall it");
+        }
+        if let Some(caveats) = pac.caveats {
+            println!("\n==> Caveats\n{}", caveats);
+        }
+        return Ok(());
+    }
+    println!("Package {} is not installed.\n", name);
+    let pac = resolve_json_api(name).await?;
+    println!(
+        "{} {}",
+        pac.name,
+        pac.versions.stable.as_deref().unwrap_or("unknown")
+    );
+    if let Some(note) = pac.disable_note().or_else(|| pac.deprecation_note()) {
+        println!("This package is {}", note);
+    }
+    if let Some(caveats) = pac.caveats_with_prefix(pac_root()) {
+        println!("\n==> Caveats\n{}", caveats);
+    }
+    Ok(())
+}
@@ -1,21 +1,20 @@
 use std::{
-    collections::HashSet,
-    ffi::OsStr,
-    fs, io,
+    collections::{BTreeMap, HashSet},
+    fs,
     path::{Path, PathBuf},
     sync::LazyLock,
 };
 
 use crate::{
-    PAC_PATH,
+    config::pac_root,
     database::local::SqlTransaction,
     errors::CatError,
-    macos::file::{
-        add_permit, copy_file_force, cp_dir_patch, cp_dir_with_record_and_check, remove_file_force,
-    },
+    macos::file::{cp_keg, link_dir, plan_link, publish_links, remove_dir_recursively_force},
+    package::journal::{self, Entry},
 };
 
-pub static DIR_TO_INSTALL: LazyLock<HashSet<&str>> = LazyLock::new(|| {
+/// Dirs of a keg that are linked into the prefix
+pub static DIR_TO_LINK: LazyLock<HashSet<&str>> = LazyLock::new(|| {
     let mut set = HashSet::new();
     set.insert("bin");
     set.insert("sbin");
@@ -24,76 +23,210 @@ pub static DIR_TO_INSTALL: LazyLock<HashSet<&str>> = LazyLock::new(|| {
     set.insert("etc");
     set.insert("share");
     set.insert("include");
-    set.insert("opt");
     set.insert("var");
-    set.insert(".bottle");
     set
 });
 
-pub async fn install<P>(
+/// Files of a package installed in the current transaction
+#[derive(Debug, Default)]
+pub struct InstalledFiles {
+    /// files in the keg
+    pub files: Vec<PathBuf>,
+    /// symlinks in the prefix and their targets
+    pub links: BTreeMap<PathBuf, PathBuf>,
+    /// links created in the prefix so far
+    pub created: Vec<PathBuf>,
+    /// files in the prefix replaced by links, and their backups
+    pub replaced: Vec<(PathBuf, PathBuf)>,
+}
+
+impl InstalledFiles {
+    pub fn paths(&self) -> impl Iterator<Item = &PathBuf> {
+        self.files.iter().chain(self.links.keys())
+    }
+}
+
+/// Every version of a package lives in its own keg, such as `/opt/pac/Cellar/fish/4.1.2`
+pub fn keg_path(name_version: &str) -> PathBuf {
+    Path::new(pac_root()).join("Cellar").join(name_version)
+}
+
+/// Like Homebrew, `/opt/pac/opt/<name>` always points to the active keg
+pub fn opt_path(name: &str) -> PathBuf {
+    Path::new(pac_root()).join("opt").join(name)
+}
+
+/// Kegs are built here before they are published, it is on the same filesystem
+/// as the prefix so publishing is a rename
+pub fn staging_dir() -> PathBuf {
+    Path::new(pac_root()).join("PacData/staging")
+}
+
+/// Links and backups of the running process are made here, it is removed when
+/// the transaction finishes
+pub fn scratch_dir() -> PathBuf {
+    scratch_dir_of(std::process::id())
+}
+
+/// The scratch dir of a process, kept when its rollback can not finish
+pub fn scratch_dir_of(pid: u32) -> PathBuf {
+    staging_dir().join(format!("{}-scratch", pid))
+}
+
+/// A keg built in the staging dir, removed on drop unless it is published
+pub struct StagedKeg {
+    staged: PathBuf,
+    keg: PathBuf,
+    name: String,
+}
+
+impl StagedKeg {
+    /// Move the keg into the Cellar and create its links, nothing in the prefix
+    /// is touched before this
+    pub fn publish(&self, installed: &mut InstalledFiles) -> Result<(), CatError> {
+        journal::record(&Entry::Keg {
+            name: self.name.clone(),
+            path: self.keg.clone(),
+        })?;
+        if let Some(parent) = self.keg.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(&self.staged, &self.keg)?;
+        publish_links(
+            &installed.links,
+            scratch_dir(),
+            &mut installed.replaced,
+            &mut installed.created,
+        )?;
+        Ok(())
+    }
+}
+
+impl Drop for StagedKeg {
+    fn drop(&mut self) {
+        if fs::symlink_metadata(&self.staged).is_ok()
+            && let Err(e) = remove_dir_recursively_force(&self.staged)
+        {
+            eprintln!(
+                "Warning: Can not remove staged keg: {}, error: {e}",
+                self.staged.display()
+            );
+        }
+    }
+}
+
+/// Build the keg in the staging dir and plan its links, unless it is keg-only.
+/// Recorded files and links use the final paths, so every check can run before publishing.
+/// `etc` and `var` in `.bottle` always go to the prefix, like Homebrew does
+pub async fn stage<P>(
     path: P,
-    installed_paths: &mut Vec<PathBuf>,
+    name_version: &str,
+    keg_only: bool,
+    installed: &mut InstalledFiles,
     tx: &mut SqlTransaction,
-) -> Result<(), CatError>
+) -> Result<StagedKeg, CatError>
 where
     P: AsRef<Path>,
 {
-    let symlinks = cp_dir_with_record_and_check(&path, PAC_PATH, installed_paths, tx).await?;
-    // check symlinks
-    // if the symbolic link is broken, just copy the
-    // original file to dst
-    for (dst, target) in symlinks {
-        let actual_target = fs::read_link(&dst)?;
-        if !fs::exists(&dst)? {
-            println!(
-                "broken symlink: {} -> {}",
-                dst.display(),
-                actual_target.display()
-            );
-            remove_file_force(&dst)?;
-            copy_file_force(&target, &dst)?;
-            println!("copy file: {} -> {}", target.display(), dst.display())
-        }
+    let (pac_name, _) = name_version
+        .split_once('/')
+        .expect("name_version is always `name/version`");
+    let keg = keg_path(name_version);
+    if fs::exists(&keg)? {
+        return Err(CatError::Pac(format!(
+            "keg already exists: {}, please remove it first",
+            keg.display()
+        )));
+    }
+    let staged = staging_dir().join(format!(
+        "{}-{}",
+        std::process::id(),
+        name_version.replace('/', "-")
+    ));
+    journal::record(&Entry::Stage {
+        path: staged.clone(),
+    })?;
+    let staged = StagedKeg {
+        staged,
+        keg,
+        name: pac_name.to_string(),
+    };
+    let _ = remove_dir_recursively_force(&staged.staged);
+    let mut files = Vec::new();
+    cp_keg(&path, &staged.staged, &mut files)?;
+    installed.files.extend(
+        files
+            .iter()
+            .filter_map(|f| f.strip_prefix(&staged.staged).ok())
+            .map(|f| staged.keg.join(f)),
+    );
+    let keg = &staged.keg;
+    let prefix = Path::new(pac_root());
+    let bottle_dir = staged.staged.join(".bottle");
+    if fs::exists(&bottle_dir)? {
+        link_dir(
+            bottle_dir,
+            prefix,
+            keg.join(".bottle"),
+            &mut installed.links,
+            tx,
+        )
+        .await?;
     }
+    if !keg_only {
+        link_keg(&staged.staged, keg, &mut installed.links, tx).await?;
+    }
+    plan_link(opt_path(pac_name), keg, &mut installed.links, tx).await?;
     // special patches
-    if let Some(pac_name) = path.as_ref().parent().and_then(|p| p.file_name()) {
-        if pac_name == OsStr::new("ca-certificates") {
-            println!("special patch for ca-certificates");
-            cp_dir_patch(
-                path.as_ref().join("share"),
-                Path::new(PAC_PATH).join("etc"),
-                installed_paths,
-                tx,
-            )
-            .await?;
-        } else if pac_name.to_string_lossy().find("openssl").is_some() {
-            println!("special patch for openssl");
-            const TARGET: &str = "../ca-certificates/cacert.pem";
-            let dst = Path::new(PAC_PATH)
-                .join("etc")
-                .join(pac_name)
-                .join("cert.pem");
-            if tx.is_path_exist(&dst).await? {
-                return Err(CatError::Pac(format!(
-                    "file path conflict: {}",
-                    dst.display()
-                )));
-            }
-            installed_paths.push(dst.to_path_buf());
-            if let Err(e) = std::os::unix::fs::symlink(TARGET, &dst) {
-                match e.kind() {
-                    io::ErrorKind::AlreadyExists => {
-                        remove_file_force(&dst)?;
-                        std::os::unix::fs::symlink(TARGET, &dst)?;
-                    }
-                    io::ErrorKind::PermissionDenied => {
-                        add_permit(&dst.parent().unwrap(), 0o200)?;
-                        std::os::unix::fs::symlink(TARGET, &dst)?;
-                    }
-                    _ => return Err(e.into()),
-                }
-            }
+    if pac_name == "ca-certificates" {
+        println!("special patch for ca-certificates");
+        link_dir(
+            staged.staged.join("share"),
+            prefix.join("etc"),
+            keg.join("share"),
+            &mut installed.links,
+            tx,
+        )
+        .await?;
+    } else if pac_name.contains("openssl") {
+        println!("special patch for openssl");
+        let dst = prefix.join("etc").join(pac_name).join("cert.pem");
+        let target = prefix.join("etc/ca-certificates/cacert.pem");
+        // replace the one shipped in the bottle, if any
+        installed.links.remove(&dst);
+        plan_link(dst, target, &mut installed.links, tx).await?;
+    }
+    Ok(staged)
+}
+
+/// Plan links for the files of a keg in the shared prefix, `.bottle` is not included.
+/// `src` is walked and the links point to the same files in `keg`
+pub async fn link_keg<P, Q>(
+    src: P,
+    keg: Q,
+    links: &mut BTreeMap<PathBuf, PathBuf>,
+    tx: &mut SqlTransaction,
+) -> Result<(), CatError>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let prefix = Path::new(pac_root());
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let file_name = file_name.to_string_lossy();
+        if !entry.file_type()?.is_dir() || !DIR_TO_LINK.contains(file_name.as_ref()) {
+            continue;
         }
+        link_dir(
+            entry.path(),
+            prefix.join(file_name.as_ref()),
+            keg.as_ref().join(file_name.as_ref()),
+            links,
+            tx,
+        )
+        .await?;
     }
     Ok(())
 }
@@ -0,0 +1,354 @@
+use std::{
+    collections::BTreeSet,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::UNIX_EPOCH,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::pac_root,
+    database::local::SqlTransaction,
+    errors::CatError,
+    macos::file::{
+        CmpPath, remove_dir_force, remove_dir_recursively_force, remove_file_force, restore_backups,
+    },
+    package::{install::scratch_dir_of, link::remove_keg_links, uninstall::remove_installed},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Install,
+    Uninstall,
+    Link,
+    Unlink,
+}
+
+/// A line of the journal, written before the operation it describes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Entry {
+    Begin {
+        action: Action,
+        pid: u32,
+    },
+    /// a keg is going to be built in the staging dir, it is always removed
+    Stage {
+        path: PathBuf,
+    },
+    /// a keg is going to be created, it is removed if the package is not recorded
+    Keg {
+        name: String,
+        path: PathBuf,
+    },
+    /// a file or symlink is going to be created in the prefix
+    Create {
+        path: PathBuf,
+    },
+    /// a file in the prefix is going to be replaced, it is moved to `backup` first
+    Backup {
+        path: PathBuf,
+        backup: PathBuf,
+    },
+    /// a package is going to be removed, it is marked broken first
+    Remove {
+        name: String,
+    },
+    /// the links of a package are going to be removed, the rest are removed
+    /// if the package is still installed
+    Unlink {
+        name: String,
+    },
+}
+
+/// The journal of the running operation, `None` when nothing is journaled
+static ACTIVE: Mutex<Option<(PathBuf, File)>> = Mutex::new(None);
+
+fn journal_dir() -> PathBuf {
+    Path::new(pac_root()).join("PacData/journal")
+}
+
+/// An on-disk journal of an operation on the prefix. It is removed when the
+/// operation commits or rolls back in process, so a journal left behind means
+/// the process was killed and `pac repair` should clean up after it.
+/// The file stays locked while its process is alive
+pub struct Journal {
+    path: PathBuf,
+}
+
+impl Journal {
+    pub fn begin(action: Action) -> Result<Self, CatError> {
+        let mut active = ACTIVE.lock().unwrap_or_else(|e| e.into_inner());
+        if active.is_some() {
+            return Err(CatError::Pac("another operation is running".to_string()));
+        }
+        let dir = journal_dir();
+        fs::create_dir_all(&dir)?;
+        let time = std::time::SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("unix epoch is always earlier than now")
+            .as_secs();
+        let pid = std::process::id();
+        let path = dir.join(format!("{time}-{pid}.jsonl"));
+        let file = OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(&path)?;
+        file.lock()?;
+        *active = Some((path.clone(), file));
+        drop(active);
+        let journal = Self { path };
+        record(&Entry::Begin { action, pid })?;
+        journal.sync()?;
+        Ok(journal)
+    }
+
+    /// Entries reach the kernel before each operation, so they survive a killed
+    /// process. Syncing them to disk for power loss is done at each package
+    pub fn sync(&self) -> Result<(), CatError> {
+        if let Some((_, file)) = ACTIVE.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        // left for `pac repair` by `keep`
+        let Some(file) = ACTIVE.lock().unwrap_or_else(|e| e.into_inner()).take() else {
+            return;
+        };
+        if let Err(e) = fs::remove_file(&self.path) {
+            eprintln!(
+                "Warning: can not remove journal {}, error: {e}",
+                self.path.display()
+            );
+        }
+        drop(file);
+    }
+}
+
+/// Append an entry to the running journal, nothing happens without one
+pub fn record(entry: &Entry) -> Result<(), CatError> {
+    let mut active = ACTIVE.lock().unwrap_or_else(|e| e.into_inner());
+    let Some((_, file)) = active.as_mut() else {
+        return Ok(());
+    };
+    let mut line = serde_json::to_string(entry)
+        .map_err(|e| CatError::Pac(format!("Can not write journal: {e}")))?;
+    line.push('\n');
+    file.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+/// Leave the running journal behind when a rollback can not finish, so
+/// `pac repair` cleans up what is left
+pub fn keep() {
+    let active = ACTIVE.lock().unwrap_or_else(|e| e.into_inner()).take();
+    if let Some((path, _)) = active {
+        eprintln!(
+            "Changes to the prefix are left as recorded in {}, run `pac repair` to clean them up",
+            path.display()
+        );
+    }
+}
+
+/// Path of the running journal
+pub fn active_path() -> Option<PathBuf> {
+    let active = ACTIVE.lock().unwrap_or_else(|e| e.into_inner());
+    active.as_ref().map(|(path, _)| path.clone())
+}
+
+/// Entries of a journal, a line cut short by a crash is ignored
+fn parse_journal(content: &str) -> Vec<Entry> {
+    content
+        .lines()
+        .map_while(|line| serde_json::from_str(line).ok())
+        .collect()
+}
+
+/// Journals left by killed processes, the ones locked by a running pac are skipped
+pub fn unfinished() -> Result<Vec<PathBuf>, CatError> {
+    let dir = journal_dir();
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut journals = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "jsonl") {
+            continue;
+        }
+        let file = File::open(&path)?;
+        match file.try_lock() {
+            Ok(()) => journals.push(path),
+            Err(fs::TryLockError::WouldBlock) => {}
+            Err(fs::TryLockError::Error(e)) => return Err(e.into()),
+        }
+    }
+    journals.sort();
+    Ok(journals)
+}
+
+/// Remove what an uncommitted install or link left, and finish interrupted
+/// uninstalls and unlinks
+async fn repair_journal(entries: &[Entry], tx: &mut SqlTransaction) -> Result<(), CatError> {
+    let mut dirs = BTreeSet::new();
+    let mut scratch = None;
+    let backups = entries
+        .iter()
+        .filter_map(|entry| match entry {
+            Entry::Backup { path, backup } => Some((path, backup)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let root = Path::new(pac_root());
+    for entry in entries {
+        match entry {
+            Entry::Begin { action, pid } => {
+                println!("repairing {:?} interrupted in process {}", action, pid);
+                scratch = Some(scratch_dir_of(*pid));
+            }
+            Entry::Stage { path } => {
+                if fs::symlink_metadata(path).is_ok() {
+                    println!("removing staged keg {}", path.display());
+                    remove_dir_recursively_force(path)?;
+                }
+            }
+            Entry::Keg { name, path } => {
+                if tx.is_installed(name).await?.is_some() || !path.exists() {
+                    continue;
+                }
+                println!("removing keg {}", path.display());
+                remove_dir_recursively_force(path)?;
+                dirs.extend(path.ancestors().skip(1).map(Path::to_path_buf));
+            }
+            Entry::Create { path } => {
+                if tx.is_path_exist(path).await? || fs::symlink_metadata(path).is_err() {
+                    continue;
+                }
+                // the replaced file is back already, by a rollback or because
+                // the process stopped before the link was made
+                if backups
+                    .iter()
+                    .any(|(p, backup)| *p == path && fs::symlink_metadata(backup).is_err())
+                {
+                    continue;
+                }
+                println!("removing {}", path.display());
+                remove_file_force(path)?;
+                dirs.extend(path.ancestors().skip(1).map(Path::to_path_buf));
+            }
+            // restored after every new file is removed
+            Entry::Backup { .. } => {}
+            Entry::Remove { name } => {
+                let Some((id, _)) = tx.is_installed(name).await? else {
+                    continue;
+                };
+                println!("finishing the uninstall of {}", name);
+                let installed_files = tx.get_installed_files(id).await?;
+                let links = tx.get_links(id).await?;
+                remove_installed(
+                    name,
+                    links.iter().map(|(p, _)| p).chain(installed_files.iter()),
+                )?;
+                tx.delete_a_pac(id).await?;
+            }
+            Entry::Unlink { name } => {
+                if tx.is_installed(name).await?.is_none() {
+                    continue;
+                }
+                println!("finishing the unlink of {}", name);
+                remove_keg_links(name, tx).await?;
+            }
+        }
+    }
+    for (path, backup) in backups.into_iter().rev() {
+        if fs::symlink_metadata(backup).is_err() {
+            continue;
+        }
+        dirs.extend(backup.ancestors().skip(1).map(Path::to_path_buf));
+        // the transaction was committed, the backup is not needed
+        if tx.is_path_exist(path).await? {
+            remove_file_force(backup)?;
+            continue;
+        }
+        println!("restoring {}", path.display());
+        restore_backups(&[(path.clone(), backup.clone())])?;
+    }
+    if let Some(scratch) = scratch
+        && fs::symlink_metadata(&scratch).is_ok()
+    {
+        remove_dir_recursively_force(&scratch)?;
+    }
+    // deepest dirs first, shared dirs that are not empty stay
+    let dirs = dirs
+        .iter()
+        .filter(|d| d.starts_with(root) && d.as_path() != root)
+        .map(CmpPath)
+        .collect::<BTreeSet<_>>();
+    for dir in dirs {
+        if let Err(e) = remove_dir_force(&*dir)
+            && !matches!(
+                e.kind(),
+                io::ErrorKind::DirectoryNotEmpty | io::ErrorKind::NotFound
+            )
+        {
+            eprintln!("Warning: Can not remove dir: {}, error: {e}", dir.display());
+        }
+    }
+    Ok(())
+}
+
+/// `pac repair`, clean up after operations interrupted by a crash or a kill
+pub async fn repair() -> Result<(), CatError> {
+    let journals = unfinished()?;
+    if journals.is_empty() {
+        println!("Nothing to repair");
+        return Ok(());
+    }
+    for path in journals {
+        let file = File::open(&path)?;
+        // a pac started after the check may have taken it
+        if file.try_lock().is_err() {
+            continue;
+        }
+        let entries = parse_journal(&fs::read_to_string(&path)?);
+        let mut tx = SqlTransaction::new().await?;
+        repair_journal(&entries, &mut tx).await?;
+        tx.commit().await?;
+        fs::remove_file(&path)?;
+    }
+    println!("Repair finished");
+    Ok(())
+}
+
+#[test]
+fn test_parse_journal() {
+    let entries = [
+        Entry::Begin {
+            action: Action::Install,
+            pid: 42,
+        },
+        Entry::Keg {
+            name: "fish".to_string(),
+            path: PathBuf::from("/opt/pac/Cellar/fish/4.1.2"),
+        },
+        Entry::Create {
+            path: PathBuf::from("/opt/pac/bin/fish"),
+        },
+    ];
+    let mut content = entries
+        .iter()
+        .map(|e| serde_json::to_string(e).unwrap() + "\n")
+        .collect::<String>();
+    assert!(content.starts_with(r#"{"op":"begin","action":"install","pid":42}"#));
+    // the process was killed in the middle of a line
+    content.push_str(r#"{"op":"create","pa"#);
+    assert_eq!(parse_journal(&content), entries);
+}
@@ -0,0 +1,154 @@
+use std::path::{Path, PathBuf};
+
+use crate::{
+    config::pac_root,
+    database::local::SqlTransaction,
+    errors::CatError,
+    macos::file::{
+        check_existing, publish_links, remove_dir_recursively_force, remove_file_force,
+        unpublish_links,
+    },
+    package::{
+        install::{InstalledFiles, link_keg, opt_path, scratch_dir},
+        journal::{self, Action, Entry, Journal},
+    },
+    scopeguard::DropGuard,
+};
+
+/// Find the installed package and its active keg
+async fn find_keg(name: &str, tx: &mut SqlTransaction) -> Result<(i64, PathBuf), CatError> {
+    let Some((id, _)) = tx.is_installed(name).await? else {
+        return Err(CatError::Pac(format!("Package {} is not installed", name)));
+    };
+    let Some(keg) = tx.get_link_target(opt_path(name)).await? else {
+        return Err(CatError::Pac(format!("Can not find the keg of {}", name)));
+    };
+    Ok((id, keg))
+}
+
+/// Links created by `link_keg` have the same path under `root` as their target in the keg.
+/// `opt/<name>`, the files from `.bottle` and the special patches are not included
+fn is_keg_link(root: &Path, keg: &Path, path: &Path, target: &Path) -> bool {
+    match (path.strip_prefix(root), target.strip_prefix(keg)) {
+        (Ok(path), Ok(target)) => path == target && !path.as_os_str().is_empty(),
+        _ => false,
+    }
+}
+
+/// Link a keg into the prefix, keg-only packages need `force`, and files pac
+/// did not make are only replaced with `overwrite`
+pub async fn link_pac(name: &str, force: bool, overwrite: bool) -> Result<(), CatError> {
+    let mut tx = SqlTransaction::new().await?;
+    let Some(pac) = tx.get_pac_info(name).await? else {
+        return Err(CatError::Pac(format!("Package {} is not installed", name)));
+    };
+    if pac.keg_only && !force {
+        return Err(CatError::Pac(format!(
+            "{} is keg-only, linking it may shadow the one provided by macOS\n\
+            Use `--force` to link it anyway",
+            name
+        )));
+    }
+    let (id, keg) = find_keg(name, &mut tx).await?;
+    let _journal = Journal::begin(Action::Link)?;
+    let linked = tx.get_links(id).await?;
+    if linked
+        .iter()
+        .any(|(p, t)| is_keg_link(Path::new(pac_root()), &keg, p, t))
+    {
+        println!("{} is already linked", name);
+        return Ok(());
+    }
+    let mut installed = DropGuard::new(InstalledFiles::default(), |installed: InstalledFiles| {
+        eprintln!("encounter an error, removing created links");
+        // backups that are not restored stay in the scratch dir for `pac repair`
+        if let Err(e) = unpublish_links(&installed.created, &installed.replaced) {
+            eprintln!("Warning: {e}");
+            journal::keep();
+            return;
+        }
+        let _ = remove_dir_recursively_force(scratch_dir());
+    });
+    link_keg(&keg, &keg, &mut installed.links, &mut tx).await?;
+    check_existing(&installed.links, overwrite)?;
+    let InstalledFiles {
+        links,
+        replaced,
+        created,
+        ..
+    } = &mut *installed;
+    publish_links(links, scratch_dir(), replaced, created)?;
+    tx.insert_links(id, &installed.links).await?;
+    tx.commit().await?;
+    let links = installed.into_inner().links;
+    let _ = remove_dir_recursively_force(scratch_dir());
+    println!("Linked {} files of {}", links.len(), name);
+    Ok(())
+}
+
+/// Remove the links of a keg from the prefix and the database, returns how many
+/// are removed. Links already removed from the disk are ignored
+pub(crate) async fn remove_keg_links(
+    name: &str,
+    tx: &mut SqlTransaction,
+) -> Result<usize, CatError> {
+    let (id, keg) = find_keg(name, tx).await?;
+    let links = tx
+        .get_links(id)
+        .await?
+        .into_iter()
+        .filter(|(p, t)| is_keg_link(Path::new(pac_root()), &keg, p, t))
+        .collect::<Vec<_>>();
+    for (p, _) in links.iter() {
+        if let Err(e) = remove_file_force(p)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            return Err(CatError::Pac(format!(
+                "Failed to remove link {}: {}",
+                p.display(),
+                e
+            )));
+        }
+        tx.delete_link(p).await?;
+    }
+    Ok(links.len())
+}
+
+/// Remove the links of a keg from the prefix, but keep the keg, `opt/<name>`
+/// and the files from `.bottle`
+pub async fn unlink_pac(name: &str) -> Result<(), CatError> {
+    let mut tx = SqlTransaction::new().await?;
+    // links removed before a kill are gone from the disk but still recorded,
+    // `pac repair` removes the rest
+    let _journal = Journal::begin(Action::Unlink)?;
+    journal::record(&Entry::Unlink {
+        name: name.to_string(),
+    })?;
+    let count = remove_keg_links(name, &mut tx).await?;
+    if count == 0 {
+        println!("{} is not linked", name);
+        return Ok(());
+    }
+    tx.commit().await?;
+    println!("Unlinked {} files of {}", count, name);
+    Ok(())
+}
+
+#[test]
+fn test_is_keg_link() {
+    let root = std::env::temp_dir().join("pac");
+    let keg = root.join("Cellar/ca-certificates/2025-09-09");
+    let link =
+        |path: &str, target: &str| is_keg_link(&root, &keg, &root.join(path), &keg.join(target));
+    assert!(link(
+        "share/ca-certificates/cacert.pem",
+        "share/ca-certificates/cacert.pem"
+    ));
+    // the special patch of ca-certificates
+    assert!(!link(
+        "etc/ca-certificates/cacert.pem",
+        "share/ca-certificates/cacert.pem"
+    ));
+    assert!(!link("etc/foo.conf", ".bottle/etc/foo.conf"));
+    assert!(!link("opt/ca-certificates", ""));
+}
@@ -0,0 +1,316 @@
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use goblin::mach::{Mach, MachO};
+use rayon::prelude::*;
+
+use crate::{
+    config::pac_root, database::local::SqlTransaction, errors::CatError,
+    package::load_path::normalize_path,
+};
+
+/// Dylibs in the dyld shared cache are not on disk since Big Sur
+const SYSTEM_LIBRARY_DIRS: [&str; 2] = ["/usr/lib/", "/System/Library/"];
+
+/// Load commands of a Mach-O that matter for linkage, merged from every slice
+#[derive(Debug, Default)]
+pub struct MachoLinks {
+    pub libs: BTreeSet<String>,
+    pub rpaths: Vec<String>,
+}
+
+/// Collect `LC_LOAD_DYLIB` and `LC_RPATH` of every slice, `None` if it is not a Mach-O
+pub fn macho_links(data: &[u8]) -> Option<MachoLinks> {
+    fn add(macho: &MachO, links: &mut MachoLinks) {
+        // the first one is the binary itself
+        links
+            .libs
+            .extend(macho.libs.iter().skip(1).map(|l| l.to_string()));
+        for rpath in macho.rpaths.iter() {
+            if !links.rpaths.iter().any(|r| r == rpath) {
+                links.rpaths.push(rpath.to_string());
+            }
+        }
+    }
+    if !infer::is(data, "mach") {
+        return None;
+    }
+    let mut links = MachoLinks::default();
+    match Mach::parse(data).ok()? {
+        Mach::Binary(macho) => add(&macho, &mut links),
+        Mach::Fat(fat) => {
+            for arch in fat.arches().ok()? {
+                if let Ok(macho) = MachO::parse(arch.slice(data), 0) {
+                    add(&macho, &mut links);
+                }
+            }
+        }
+    }
+    Some(links)
+}
+
+/// Expand `@loader_path` and `@executable_path` against the binary. The
+/// executable of a dylib is unknown, so it is treated like `@loader_path`
+fn expand_path(path: &str, binary: &Path) -> Option<PathBuf> {
+    let dir = binary.parent().unwrap_or(Path::new("/"));
+    for token in ["@loader_path", "@executable_path"] {
+        if let Some(rest) = path.strip_prefix(token) {
+            let rest = rest.trim_start_matches('/');
+            return Some(normalize_path(&dir.join(rest)));
+        }
+    }
+    path.starts_with('/')
+        .then(|| normalize_path(Path::new(path)))
+}
+
+/// Paths dyld may load a dylib from, in search order
+pub fn candidate_paths(lib: &str, binary: &Path, rpaths: &[String]) -> Vec<PathBuf> {
+    if let Some(rest) = lib.strip_prefix("@rpath/") {
+        return rpaths
+            .iter()
+            .filter_map(|rpath| expand_path(rpath, binary))
+            .map(|dir| normalize_path(&dir.join(rest)))
+            .collect();
+    }
+    expand_path(lib, binary).into_iter().collect()
+}
+
+pub fn is_system_library(path: &Path) -> bool {
+    SYSTEM_LIBRARY_DIRS.iter().any(|dir| path.starts_with(dir))
+}
+
+/// Where a dependency of a binary is found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Found {
+    /// installed by pac, the path in the keg and its owner
+    Pac {
+        path: PathBuf,
+        owner: String,
+    },
+    System(PathBuf),
+    /// exists, but is not installed by pac nor a system library
+    Unowned(PathBuf),
+    Missing,
+}
+
+#[derive(Debug)]
+pub struct Dependency {
+    pub binary: PathBuf,
+    pub lib: String,
+    pub found: Found,
+}
+
+/// Resolve files of the install root through symlinks, to find the file
+/// recorded in the database
+pub struct Resolver {
+    root: PathBuf,
+    real_root: PathBuf,
+}
+
+impl Resolver {
+    pub fn new() -> Self {
+        let root = PathBuf::from(pac_root());
+        let real_root = fs::canonicalize(&root).unwrap_or_else(|_| root.clone());
+        Self { root, real_root }
+    }
+
+    /// Keep the root as recorded, even if it is under a symlink such as `/tmp`
+    fn real_path(&self, path: &Path) -> Option<PathBuf> {
+        let real = fs::canonicalize(path).ok()?;
+        match real.strip_prefix(&self.real_root) {
+            Ok(rest) => Some(self.root.join(rest)),
+            Err(_) => Some(real),
+        }
+    }
+
+    pub async fn resolve(
+        &self,
+        lib: &str,
+        binary: &Path,
+        rpaths: &[String],
+        tx: &mut SqlTransaction,
+    ) -> Result<Found, CatError> {
+        let mut unowned = None;
+        for candidate in candidate_paths(lib, binary, rpaths) {
+            if is_system_library(&candidate) {
+                return Ok(Found::System(candidate));
+            }
+            let Some(real) = self.real_path(&candidate) else {
+                continue;
+            };
+            if let Some(owner) = tx.get_path_owner(&real).await? {
+                return Ok(Found::Pac { path: real, owner });
+            }
+            unowned.get_or_insert(real);
+        }
+        Ok(unowned.map_or(Found::Missing, Found::Unowned))
+    }
+
+    /// Resolve every dylib loaded by the Mach-O files among `files`
+    pub async fn linkage(
+        &self,
+        files: &[PathBuf],
+        tx: &mut SqlTransaction,
+    ) -> Result<Vec<Dependency>, CatError> {
+        let binaries = files
+            .par_iter()
+            .filter_map(|file| match fs::symlink_metadata(file) {
+                Ok(meta) if meta.is_file() => Some(file),
+                _ => None,
+            })
+            .map(|file| Ok((file, fs::read(file).map(|data| macho_links(&data))?)))
+            .collect::<Result<Vec<_>, CatError>>()?;
+        let mut dependencies = Vec::new();
+        for (binary, links) in binaries {
+            let Some(links) = links else {
+                continue;
+            };
+            for lib in links.libs {
+                let found = self.resolve(&lib, binary, &links.rpaths, tx).await?;
+                dependencies.push(Dependency {
+                    binary: binary.clone(),
+                    lib,
+                    found,
+                });
+            }
+        }
+        Ok(dependencies)
+    }
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Binaries of a package being installed and the dylibs of other packages they load,
+/// recorded so uninstalling those packages can tell what would break
+pub async fn cross_linkage(
+    name: &str,
+    files: &[PathBuf],
+    tx: &mut SqlTransaction,
+) -> Result<Vec<(PathBuf, PathBuf)>, CatError> {
+    let dependencies = Resolver::new().linkage(files, tx).await?;
+    let linkage = dependencies
+        .into_iter()
+        .filter_map(|dep| match dep.found {
+            Found::Pac { path, owner } if owner != name => Some((dep.binary, path)),
+            _ => None,
+        })
+        .collect();
+    Ok(linkage)
+}
+
+fn print_section<I>(title: &str, items: I)
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut items = items.into_iter().peekable();
+    if items.peek().is_none() {
+        return;
+    }
+    println!("  {title}:");
+    for item in items {
+        println!("    {item}");
+    }
+}
+
+/// Check the linkage of a package, returns the number of missing dylibs
+async fn check_a_pac(
+    name: &str,
+    resolver: &Resolver,
+    tx: &mut SqlTransaction,
+) -> Result<usize, CatError> {
+    let Some((id, _)) = tx.is_installed(name).await? else {
+        return Err(CatError::Pac(format!("Package {} is not installed", name)));
+    };
+    let files = tx.get_installed_files(id).await?;
+    let dependencies = resolver.linkage(&files, tx).await?;
+    let declared = tx.get_deps(id).await?;
+
+    let mut system = BTreeSet::new();
+    let mut pac = BTreeSet::new();
+    let mut unowned = BTreeSet::new();
+    let mut missing = BTreeSet::new();
+    let mut undeclared = BTreeSet::new();
+    for dep in dependencies.iter() {
+        match &dep.found {
+            Found::Pac { path, owner } => {
+                pac.insert(format!("{} ({})", path.display(), owner));
+                if owner != name && !declared.contains(owner) {
+                    undeclared.insert(owner.clone());
+                }
+            }
+            Found::System(path) => {
+                system.insert(path.display().to_string());
+            }
+            Found::Unowned(path) => {
+                unowned.insert(path.display().to_string());
+            }
+            Found::Missing => {
+                missing.insert(format!("{} ({})", dep.lib, dep.binary.display()));
+            }
+        }
+    }
+    println!("{}:", name);
+    if dependencies.is_empty() {
+        println!("  No linked libraries");
+    }
+    print_section("System libraries", system);
+    print_section("Pac libraries", pac);
+    print_section("Libraries not installed by pac", unowned);
+    print_section("Missing libraries", missing.iter().cloned());
+    print_section("Undeclared dependencies with linkage", undeclared);
+    Ok(missing.len())
+}
+
+/// `pac linkage`, check the dylibs loaded by one package or by every package in the root
+pub async fn check_linkage(name: Option<&str>) -> Result<(), CatError> {
+    let mut tx = SqlTransaction::new().await?;
+    let names = match name {
+        Some(name) => vec![name.to_string()],
+        None => tx.get_pac_names().await?,
+    };
+    let resolver = Resolver::new();
+    let mut missing = 0;
+    for name in names.iter() {
+        missing += check_a_pac(name, &resolver, &mut tx).await?;
+    }
+    if missing > 0 {
+        return Err(CatError::Pac(format!("{} libraries are missing", missing)));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_candidate_paths() {
+    let binary = Path::new("/opt/pac/Cellar/glib/2.86.0/bin/gio");
+    let rpaths = [
+        "@loader_path/../lib".to_string(),
+        "@executable_path/../../../../opt/gettext/lib".to_string(),
+        "/opt/pac/lib".to_string(),
+        "relative/lib".to_string(),
+    ];
+    assert_eq!(
+        candidate_paths("@rpath/libintl.8.dylib", binary, &rpaths),
+        [
+            "/opt/pac/Cellar/glib/2.86.0/lib/libintl.8.dylib",
+            "/opt/pac/opt/gettext/lib/libintl.8.dylib",
+            "/opt/pac/lib/libintl.8.dylib",
+        ]
+        .map(PathBuf::from)
+    );
+    assert_eq!(
+        candidate_paths("@loader_path/../lib/libgio-2.0.0.dylib", binary, &rpaths),
+        [PathBuf::from(
+            "/opt/pac/Cellar/glib/2.86.0/lib/libgio-2.0.0.dylib"
+        )]
+    );
+    let system = candidate_paths("/usr/lib/libSystem.B.dylib", binary, &rpaths);
+    assert!(is_system_library(&system[0]));
+    assert!(candidate_paths("libfoo.dylib", binary, &rpaths).is_empty());
+}
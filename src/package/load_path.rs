@@ -1,119 +1,426 @@
 use crate::{
-    BREW_CELLAR, BREW_CELLAR_ACTUAL, BREW_PREFIX, PAC_PATH, errors::CatError, macos::version::ARCH,
+    BREW_CELLAR, BREW_CELLAR_ACTUAL, BREW_PREFIX, config::pac_root,
+    database::local::SqlTransaction, errors::CatError, package::install::InstalledFiles,
 };
 use arwen::macho::MachoContainer;
 use goblin::mach::{Mach, MachO};
-use std::{io, path::Path};
+use std::{
+    collections::{BTreeSet, HashSet},
+    fmt, fs, io,
+    path::{Component, Path, PathBuf},
+};
 
-fn handle_single_binary(mach: MachO) -> Result<Vec<String>, io::Error> {
-    let load_paths = mach.libs.iter().map(|s| s.to_string()).collect::<Vec<_>>();
-    if !load_paths.is_empty() {
-        Ok(load_paths)
+pub fn describe_arch(cputype: u32) -> &'static str {
+    match cputype {
+        0x01000007 => "x86_64",
+        0x0100000C => "arm64",
+        _ => "unknown",
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadCommandKind {
+    IdDylib,
+    LoadDylib,
+    Rpath,
+}
+
+impl LoadCommandKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LoadCommandKind::IdDylib => "LC_ID_DYLIB",
+            LoadCommandKind::LoadDylib => "LC_LOAD_DYLIB",
+            LoadCommandKind::Rpath => "LC_RPATH",
+        }
+    }
+}
+
+/// A load command changed in one slice of a binary
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoadCommandChange {
+    pub arch: &'static str,
+    pub kind: LoadCommandKind,
+    pub old: String,
+    /// `None` if the command is deleted
+    pub new: Option<String>,
+}
+
+impl fmt::Display for LoadCommandChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.new {
+            Some(new) => write!(
+                f,
+                "[{}] {} {} -> {}",
+                self.arch,
+                self.kind.as_str(),
+                self.old,
+                new
+            ),
+            None => write!(
+                f,
+                "[{}] {} {} (deleted)",
+                self.arch,
+                self.kind.as_str(),
+                self.old
+            ),
+        }
+    }
+}
+
+/// Map a load path of Homebrew to the path in our prefix, the subpath is kept,
+/// so `@@HOMEBREW_PREFIX@@/opt/python@3.12/Frameworks/...` stays under `opt/python@3.12`
+fn map_load_path(p: &str, prefix: &str) -> Option<String> {
+    if let Some(rest) = p.strip_prefix(BREW_CELLAR) {
+        Some(format!("{prefix}/Cellar{rest}"))
+    } else if let Some(rest) = p.strip_prefix(BREW_CELLAR_ACTUAL) {
+        Some(format!("{prefix}/Cellar{rest}"))
     } else {
-        Err(io::Error::new(
-            io::ErrorKind::NotFound,
-            "Expected load paths in Mach-O binary",
-        ))
-    }
-}
-
-fn list_lib_path(binary: &[u8]) -> Result<Vec<String>, io::Error> {
-    match Mach::parse(&binary).map_err(|e| io::Error::other(e))? {
-        goblin::mach::Mach::Binary(bin) => return handle_single_binary(bin),
-        goblin::mach::Mach::Fat(bin) => {
-            for arch in bin.arches().map_err(|e| io::Error::other(e))? {
-                if describe_arch(arch.cputype) == ARCH {
-                    let start = arch.offset as usize;
-                    let end = start + arch.size as usize;
-                    let binary = &binary[start..end];
-                    let o = Mach::parse(binary).map_err(|e| io::Error::other(e))?;
-                    if let goblin::mach::Mach::Binary(bin) = o {
-                        return handle_single_binary(bin);
-                    } else {
-                        return Err(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            "Expected Mach-O binary inside fat binary",
-                        ));
-                    }
-                }
-            }
+        p.strip_prefix(BREW_PREFIX)
+            .map(|rest| format!("{prefix}{rest}"))
+    }
+}
+
+/// Plan the changes of a single arch Mach-O, every command is looked up again
+/// by its old value when applying, so the order does not matter
+fn plan_changes(macho: &MachO, prefix: &str) -> Vec<LoadCommandChange> {
+    let arch = describe_arch(macho.header.cputype);
+    let mut changes = Vec::new();
+    if let Some(id) = macho.name
+        && let Some(new) = map_load_path(id, prefix)
+    {
+        changes.push(LoadCommandChange {
+            arch,
+            kind: LoadCommandKind::IdDylib,
+            old: id.to_string(),
+            new: Some(new),
+        });
+    }
+    // the first one is always the binary itself
+    for lib in macho.libs.iter().skip(1) {
+        if let Some(new) = map_load_path(lib, prefix) {
+            changes.push(LoadCommandChange {
+                arch,
+                kind: LoadCommandKind::LoadDylib,
+                old: lib.to_string(),
+                new: Some(new),
+            });
+        }
+    }
+    // dyld refuses duplicated rpaths, which may appear after mapping
+    let mut rpaths = HashSet::new();
+    for rpath in macho.rpaths.iter() {
+        let new = map_load_path(rpath, prefix);
+        let kept = new.as_deref().unwrap_or(rpath);
+        if !rpaths.insert(kept.to_string()) {
+            changes.push(LoadCommandChange {
+                arch,
+                kind: LoadCommandKind::Rpath,
+                old: rpath.to_string(),
+                new: None,
+            });
+        } else if let Some(new) = new {
+            changes.push(LoadCommandChange {
+                arch,
+                kind: LoadCommandKind::Rpath,
+                old: rpath.to_string(),
+                new: Some(new),
+            });
         }
     }
+    changes
+}
 
-    Err(io::Error::new(
-        io::ErrorKind::NotFound,
-        "Expected load paths in Mach-O binary",
-    ))
+/// Apply one change to a single arch Mach-O
+fn apply_change(data: Vec<u8>, change: &LoadCommandChange) -> Result<Vec<u8>, CatError> {
+    let mut macho = MachoContainer::parse(&data)?;
+    match (change.kind, change.new.as_deref()) {
+        (LoadCommandKind::IdDylib, Some(new)) => macho.change_install_id(new)?,
+        (LoadCommandKind::LoadDylib, Some(new)) => macho.change_install_name(&change.old, new)?,
+        (LoadCommandKind::Rpath, Some(new)) => macho.change_rpath(&change.old, new)?,
+        (LoadCommandKind::Rpath, None) => macho.remove_rpath(&change.old)?,
+        (kind, None) => {
+            return Err(CatError::Pac(format!(
+                "can not delete {} {}",
+                kind.as_str(),
+                change.old
+            )));
+        }
+    }
+    Ok(macho.data)
 }
 
-fn describe_arch(cputype: u32) -> &'static str {
-    match cputype {
-        0x01000007 => "x86_64",
-        0x0100000C => "arm64",
-        _ => "unknown",
+/// Size of a load command holding `s`, the same as `arwen` builds it
+fn command_size(kind: LoadCommandKind, s: &str) -> usize {
+    let header = match kind {
+        LoadCommandKind::Rpath => 12,
+        LoadCommandKind::IdDylib | LoadCommandKind::LoadDylib => 24,
+    };
+    (header + s.len() + 1).div_ceil(8) * 8
+}
+
+/// Free space between the load commands and the first section
+fn header_pad(macho: &MachO, len: usize) -> Result<usize, io::Error> {
+    let header_size = if macho.is_64 { 32 } else { 28 };
+    let used = header_size + macho.header.sizeofcmds as usize;
+    let mut first_section = len;
+    for segment in macho.segments.iter() {
+        for (section, _) in segment.sections().map_err(io::Error::other)? {
+            if section.offset > 0 && section.size > 0 {
+                first_section = first_section.min(section.offset as usize);
+            }
+        }
     }
+    Ok(first_section.saturating_sub(used))
 }
 
+fn patch_slice(data: &[u8], prefix: &str) -> Result<(Vec<u8>, Vec<LoadCommandChange>), CatError> {
+    let macho = MachO::parse(data, 0).map_err(io::Error::other)?;
+    let changes = plan_changes(&macho, prefix);
+    // longer load commands take the header pad, which is usually there
+    // since Homebrew builds with `-headerpad_max_install_names`
+    let growth = changes
+        .iter()
+        .map(|c| {
+            let old = command_size(c.kind, &c.old) as isize;
+            let new = c.new.as_deref().map_or(0, |n| command_size(c.kind, n)) as isize;
+            new - old
+        })
+        .sum::<isize>();
+    let pad = header_pad(&macho, data.len())?;
+    if growth > pad as isize {
+        return Err(CatError::Relocate(format!(
+            "load commands need {} more bytes, but only {} bytes of header pad are left",
+            growth, pad
+        )));
+    }
+    let mut data = data.to_vec();
+    for change in changes.iter() {
+        data = apply_change(data, change)?;
+    }
+    Ok((data, changes))
+}
+
+/// Rewrite `LC_ID_DYLIB`, `LC_LOAD_DYLIB` and `LC_RPATH` of every slice,
+/// returns the patched binary and the changed load commands
 pub fn modify_load_path(
     mut binary: Vec<u8>,
-    prefix_with_version: &str,
-) -> Result<Vec<u8>, CatError> {
-    let paths = list_lib_path(&binary)?;
-    let prefix_1 = format!("{}/{}", BREW_CELLAR, prefix_with_version);
-    let prefix_2 = format!("{}/{}", BREW_CELLAR_ACTUAL, prefix_with_version);
-    let mut path_iter = paths.into_iter();
-    if let Some(p) = path_iter.next()
-        && p != "self"
-    {
-        if p.contains(BREW_PREFIX) {
-            let path = Path::new(&p);
-            let file_name = path.file_name().unwrap();
-            let new_p = format!("{}/lib/{}", PAC_PATH, file_name.to_string_lossy());
-            // println!("new path: {}", new_p);
-            let mut macho = MachoContainer::parse(&binary)?;
-            macho.change_install_id(&new_p)?;
-            binary = macho.data;
+    prefix: &str,
+) -> Result<(Vec<u8>, Vec<LoadCommandChange>), CatError> {
+    let arches = match Mach::parse(&binary).map_err(io::Error::other)? {
+        Mach::Binary(_) => return patch_slice(&binary, prefix),
+        Mach::Fat(fat) => fat.arches().map_err(io::Error::other)?,
+    };
+    let mut all_changes = Vec::new();
+    for arch in arches {
+        let range = arch.offset as usize..(arch.offset + arch.size) as usize;
+        let Some(slice) = binary.get(range.clone()) else {
+            return Err(CatError::Pac("fat arch is out of range".to_string()));
+        };
+        let (patched, changes) = patch_slice(slice, prefix)?;
+        // the header pad is used, so the size of a slice never changes
+        if patched.len() != range.len() {
+            return Err(CatError::Pac(format!(
+                "size of the {} slice is changed after patching",
+                describe_arch(arch.cputype)
+            )));
         }
-        if p.contains(&prefix_1) {
-            let new_p = p.replacen(&prefix_1, PAC_PATH, 1);
-            // println!("new path with version: {}", new_p);
-            let mut macho = MachoContainer::parse(&binary)?;
-            macho.change_install_id(&new_p)?;
-            binary = macho.data;
-        }
-        if p.contains(&prefix_2) {
-            let new_p = p.replacen(&prefix_2, PAC_PATH, 1);
-            // println!("new path with version: {}", new_p);
-            let mut macho = MachoContainer::parse(&binary)?;
-            macho.change_install_id(&new_p)?;
-            binary = macho.data;
+        binary[range].copy_from_slice(&patched);
+        all_changes.extend(changes);
+    }
+    Ok((binary, all_changes))
+}
+
+/// Remove `.` and `..` without touching the file system
+pub(crate) fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            c => normalized.push(c),
         }
     }
-    for p in path_iter {
-        if p.contains(BREW_PREFIX) {
-            let path = Path::new(&p);
-            let file_name = path.file_name().unwrap();
-            let new_p = format!("{}/lib/{}", PAC_PATH, file_name.to_string_lossy());
-            let mut macho = MachoContainer::parse(&binary)?;
-            macho.change_install_name(&p, &new_p)?;
+    normalized
+}
 
-            binary = macho.data;
+async fn is_recorded(
+    path: &Path,
+    installed: &InstalledFiles,
+    tx: &mut SqlTransaction,
+) -> Result<bool, CatError> {
+    if installed.paths().any(|p| p == path) {
+        return Ok(true);
+    }
+    tx.is_path_exist(path).await
+}
+
+/// Check the load path points to a file installed by pac. Symlinked dirs such as
+/// `opt/<name>` or `Versions/Current` are followed when they are installed by pac too
+async fn is_installed_target(
+    target: &Path,
+    installed: &InstalledFiles,
+    tx: &mut SqlTransaction,
+) -> Result<bool, CatError> {
+    let prefix = Path::new(pac_root());
+    let mut path = normalize_path(target);
+    // limit the depth, in case of symlink loops
+    'resolve: for _ in 0..16 {
+        if is_recorded(&path, installed, tx).await? {
+            return Ok(true);
         }
-        if p.contains(&prefix_1) {
-            let new_p = p.replacen(&prefix_1, PAC_PATH, 1);
-            // println!("new path with version: {}", new_p);
-            let mut macho = MachoContainer::parse(&binary)?;
-            macho.change_install_name(&p, &new_p)?;
-            binary = macho.data;
+        for ancestor in path.ancestors().skip(1) {
+            if ancestor == prefix || !ancestor.starts_with(prefix) {
+                break;
+            }
+            if !is_recorded(ancestor, installed, tx).await? {
+                continue;
+            }
+            // links of the current transaction are not published yet
+            let link = match installed.links.get(ancestor) {
+                Some(target) => target.clone(),
+                None => match fs::read_link(ancestor) {
+                    Ok(link) => link,
+                    Err(_) => return Ok(false),
+                },
+            };
+            let rest = path.strip_prefix(ancestor).unwrap();
+            let parent = ancestor.parent().unwrap_or(prefix);
+            path = normalize_path(&parent.join(link).join(rest));
+            continue 'resolve;
         }
-        if p.contains(&prefix_2) {
-            let new_p = p.replacen(&prefix_2, PAC_PATH, 1);
-            // println!("new path with version: {}", new_p);
-            let mut macho = MachoContainer::parse(&binary)?;
-            macho.change_install_name(&p, &new_p)?;
-            binary = macho.data;
+        return Ok(false);
+    }
+    Ok(false)
+}
+
+/// Make sure every rewritten load path of a package exists in the current
+/// transaction or in the database, so we never install a binary that can not be loaded
+pub async fn verify_load_paths(
+    name: &str,
+    targets: &BTreeSet<String>,
+    installed: &InstalledFiles,
+    tx: &mut SqlTransaction,
+) -> Result<(), CatError> {
+    let mut missing = Vec::new();
+    for target in targets {
+        if !is_installed_target(Path::new(target), installed, tx).await? {
+            missing.push(target.as_str());
         }
     }
+    if missing.is_empty() {
+        return Ok(());
+    }
+    Err(CatError::Pac(format!(
+        "{} links to libraries that are not installed:\n  {}",
+        name,
+        missing.join("\n  ")
+    )))
+}
+
+#[test]
+fn test_map_load_path() {
+    assert_eq!(
+        map_load_path(
+            "@@HOMEBREW_PREFIX@@/opt/python@3.12/Frameworks/Python.framework/Versions/3.12/Python",
+            "/opt/pac"
+        )
+        .unwrap(),
+        "/opt/pac/opt/python@3.12/Frameworks/Python.framework/Versions/3.12/Python"
+    );
+    assert_eq!(
+        map_load_path(
+            "@@HOMEBREW_PREFIX@@/lib/gcc/current/libgcc_s.1.1.dylib",
+            "/opt/pac"
+        )
+        .unwrap(),
+        "/opt/pac/lib/gcc/current/libgcc_s.1.1.dylib"
+    );
+    assert_eq!(
+        map_load_path(
+            "/opt/homebrew/Cellar/fish/4.1.2/lib/libfoo.dylib",
+            "/opt/pac"
+        )
+        .unwrap(),
+        "/opt/pac/Cellar/fish/4.1.2/lib/libfoo.dylib"
+    );
+    assert!(map_load_path("/usr/lib/libSystem.B.dylib", "/opt/pac").is_none());
+    assert_eq!(
+        normalize_path(Path::new("/opt/pac/opt/fish/../../Cellar/fish/./4.1.2")),
+        Path::new("/opt/pac/Cellar/fish/4.1.2")
+    );
+}
+
+/// Build a minimal 64-bit dylib with the given load commands and some header pad
+#[test]
+fn test_modify_load_path() {
+    use crate::macos::fixture::{ARM64, Fixture, X86_64, fat};
+    let lib = "@@HOMEBREW_PREFIX@@/opt/gettext/lib/libintl.8.dylib";
+    let fat = fat(&[
+        Fixture::dylib(
+            ARM64,
+            "@@HOMEBREW_PREFIX@@/opt/glib/lib/libglib-2.0.0.dylib",
+        )
+        .load_dylib(lib)
+        .load_dylib("/usr/lib/libSystem.B.dylib")
+        .rpath("@@HOMEBREW_PREFIX@@/lib")
+        .rpath("@loader_path/../lib")
+        .build(),
+        Fixture::dylib(
+            X86_64,
+            "/opt/homebrew/Cellar/glib/2.86.0/lib/libglib-2.0.0.dylib",
+        )
+        .load_dylib(lib)
+        .rpath("@@HOMEBREW_CELLAR@@/glib/2.86.0/lib")
+        .rpath("/opt/homebrew/Cellar/glib/2.86.0/lib")
+        .build(),
+    ]);
+
+    let (patched, changes) = modify_load_path(fat, "/opt/pac").unwrap();
+    let report = changes.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+    assert_eq!(
+        report,
+        [
+            "[arm64] LC_ID_DYLIB @@HOMEBREW_PREFIX@@/opt/glib/lib/libglib-2.0.0.dylib -> /opt/pac/opt/glib/lib/libglib-2.0.0.dylib",
+            "[arm64] LC_LOAD_DYLIB @@HOMEBREW_PREFIX@@/opt/gettext/lib/libintl.8.dylib -> /opt/pac/opt/gettext/lib/libintl.8.dylib",
+            "[arm64] LC_RPATH @@HOMEBREW_PREFIX@@/lib -> /opt/pac/lib",
+            "[x86_64] LC_ID_DYLIB /opt/homebrew/Cellar/glib/2.86.0/lib/libglib-2.0.0.dylib -> /opt/pac/Cellar/glib/2.86.0/lib/libglib-2.0.0.dylib",
+            "[x86_64] LC_LOAD_DYLIB @@HOMEBREW_PREFIX@@/opt/gettext/lib/libintl.8.dylib -> /opt/pac/opt/gettext/lib/libintl.8.dylib",
+            "[x86_64] LC_RPATH @@HOMEBREW_CELLAR@@/glib/2.86.0/lib -> /opt/pac/Cellar/glib/2.86.0/lib",
+            "[x86_64] LC_RPATH /opt/homebrew/Cellar/glib/2.86.0/lib (deleted)",
+        ]
+    );
+    let Mach::Fat(fat) = Mach::parse(&patched).unwrap() else {
+        panic!("expected a fat binary");
+    };
+    for arch in fat.arches().unwrap() {
+        let macho = MachO::parse(arch.slice(&patched), 0).unwrap();
+        assert_eq!(macho.libs[1], "/opt/pac/opt/gettext/lib/libintl.8.dylib");
+        assert!(macho.rpaths.iter().all(|r| !r.contains("homebrew")));
+        assert!(macho.name.unwrap().starts_with("/opt/pac"));
+    }
+}
 
-    Ok(binary)
+#[test]
+fn test_modify_load_path_long_prefix() {
+    use crate::macos::fixture::{ARM64, Fixture};
+    let long_prefix = "/Users/someone/.local/share/pac";
+    let lib = "@@HOMEBREW_PREFIX@@/opt/gettext/lib/libintl.8.dylib";
+    let binary = Fixture::dylib(ARM64, "/usr/lib/libfoo.dylib")
+        .load_dylib(lib)
+        .build();
+    let (patched, _) = modify_load_path(binary.clone(), long_prefix).unwrap();
+    let macho = MachO::parse(&patched, 0).unwrap();
+    assert_eq!(
+        macho.libs[1],
+        format!("{long_prefix}/opt/gettext/lib/libintl.8.dylib")
+    );
+    // no header pad left
+    let sizeofcmds = u32::from_le_bytes(binary[20..24].try_into().unwrap()) as usize;
+    let mut binary = binary;
+    binary.truncate(32 + sizeofcmds);
+    let Err(CatError::Relocate(_)) = modify_load_path(binary, long_prefix) else {
+        panic!("expected a relocate error");
+    };
 }
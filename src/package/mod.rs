@@ -1,7 +1,14 @@
+pub mod audit;
 pub mod find_depend;
+pub mod info;
 pub mod install;
+pub mod journal;
+pub mod link;
+pub mod linkage;
 pub mod list;
 pub mod load_path;
+pub mod outdated;
+pub mod plan;
 pub mod preprocess;
 pub mod relocate;
 pub mod sandbox;
@@ -0,0 +1,61 @@
+use crate::{
+    brew_api::resolve_json_api, database::local::SqlTransaction, errors::CatError,
+    package::find_depend::migrate_renamed,
+};
+
+pub async fn list_outdated() -> Result<(), CatError> {
+    let mut tx = SqlTransaction::new().await?;
+    let pacs = tx.get_pac_versions().await?;
+    if pacs.is_empty() {
+        println!("No packages installed.");
+        return Ok(());
+    }
+    println!("checking {} installed packages...", pacs.len());
+    let futs = pacs
+        .iter()
+        .map(|(name, _)| resolve_json_api(name))
+        .collect::<Vec<_>>();
+    let infos = futures::future::join_all(futs).await;
+
+    let mut outdated = Vec::new();
+    let mut retired = Vec::new();
+    for ((name, version), info) in pacs.iter().zip(infos) {
+        let info = match info {
+            Ok(info) => info,
+            Err(e) => {
+                eprintln!("Warning: can not get info of {name}, error: {e}");
+                continue;
+            }
+        };
+        // renamed upstream, keep tracking it under the new name
+        if info.name != *name {
+            migrate_renamed(&info, &mut tx).await?;
+        }
+        let name = &info.name;
+        if let Some(latest) = info.versions.stable.as_deref()
+            && latest != version
+        {
+            outdated.push(format!("{name} {version} -> {latest}"));
+        }
+        if let Some(note) = info.disable_note().or_else(|| info.deprecation_note()) {
+            retired.push(format!("{name} ({note})"));
+        }
+    }
+    tx.commit().await?;
+
+    if outdated.is_empty() {
+        println!("All packages are up to date.");
+    } else {
+        println!("Outdated packages:");
+        for line in outdated {
+            println!("  {line}");
+        }
+    }
+    if !retired.is_empty() {
+        println!("\nPackages deprecated or disabled upstream:");
+        for line in retired {
+            println!("  {line}");
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,143 @@
+use std::rc::Rc;
+
+use crate::{
+    brew_api::{File, PacInfo},
+    errors::CatError,
+    macos::version::{ARCH, ARCH_OS},
+    package::find_depend::{DependPolicy, OsDep, OsDepDecision},
+};
+
+#[derive(Debug)]
+pub struct PlanEntry {
+    pub pac: Rc<PacInfo>,
+    /// runtime dependencies, including the ones from `uses_from_macos` we install
+    pub deps: Vec<String>,
+    /// decisions for `uses_from_macos`
+    pub os_deps: Vec<OsDep>,
+    /// the bottle tag we chose, such as `arm64_sonoma` or `all`
+    pub bottle_tag: String,
+    pub bottle_file: File,
+}
+
+impl AsRef<PacInfo> for PlanEntry {
+    fn as_ref(&self) -> &PacInfo {
+        &self.pac
+    }
+}
+
+/// Packages that are going to be installed, dependencies come before dependents
+#[derive(Debug)]
+pub struct InstallPlan {
+    pub entries: Vec<PlanEntry>,
+}
+
+impl InstallPlan {
+    /// Build the plan and choose a bottle for every package, bottles built for older
+    /// macOS releases are only accepted with `allow_fallback`
+    pub fn new(
+        pacs: Vec<Rc<PacInfo>>,
+        policy: &DependPolicy,
+        allow_fallback: bool,
+    ) -> Result<Self, CatError> {
+        let mut entries = Vec::with_capacity(pacs.len());
+        for pac in pacs {
+            let Some(bottle) = pac.bottle.as_ref().and_then(|b| b.stable.as_ref()) else {
+                return Err(CatError::Pac(format!(
+                    "Package {} has no stable bottle",
+                    pac.full_name,
+                )));
+            };
+            let Some((bottle_tag, bottle_file)) = bottle.select(ARCH, policy.os, allow_fallback)
+            else {
+                let hint = if allow_fallback {
+                    ""
+                } else {
+                    ", bottles for older macOS releases are not allowed"
+                };
+                return Err(CatError::Pac(format!(
+                    "Package {} has no stable bottle for `{}` or `all` channel{}",
+                    pac.full_name,
+                    ARCH_OS.as_str(),
+                    hint
+                )));
+            };
+            let bottle_file = bottle_file.clone();
+            entries.push(PlanEntry {
+                deps: policy.runtime_deps(&pac),
+                os_deps: policy.macos_deps(&pac),
+                bottle_tag,
+                bottle_file,
+                pac,
+            });
+        }
+        Ok(Self { entries })
+    }
+
+    pub fn print(&self) {
+        println!("Packages to install:");
+        for entry in self.entries.iter() {
+            let pac = &entry.pac;
+            let version = pac.versions.stable.as_deref().unwrap_or("unknown");
+            let mut bottle = entry.bottle_tag.clone();
+            if bottle != "all" && bottle != *ARCH_OS {
+                bottle.push_str(", built for an older macOS");
+            }
+            if pac.keg_only {
+                bottle.push_str(", keg-only");
+            }
+            match pac.disable_note().or_else(|| pac.deprecation_note()) {
+                Some(note) => println!("  {} {} [{}] ({})", pac.name, version, bottle, note),
+                None => println!("  {} {} [{}]", pac.name, version, bottle),
+            }
+            for dep in entry.os_deps.iter() {
+                match dep.decision {
+                    OsDepDecision::System => {
+                        println!("      {}: provided by macOS", dep.name)
+                    }
+                    OsDepDecision::NewerThanOs(since) => {
+                        println!(
+                            "      {}: install the bottle, macOS provides it since {since}",
+                            dep.name
+                        )
+                    }
+                    OsDepDecision::Forced => {
+                        println!("      {}: install the bottle (forced)", dep.name)
+                    }
+                }
+            }
+        }
+        println!();
+        for entry in self.entries.iter() {
+            if let Some(note) = entry.pac.deprecation_note() {
+                eprintln!("Warning: {} is {}", entry.pac.name, note);
+            }
+        }
+    }
+
+    /// Refuse disabled packages, unless `force` is set
+    pub fn check(&self, force: bool) -> Result<(), CatError> {
+        let disabled = self
+            .entries
+            .iter()
+            .filter_map(|e| e.pac.disable_note().map(|note| (&e.pac.name, note)))
+            .collect::<Vec<_>>();
+        if disabled.is_empty() {
+            return Ok(());
+        }
+        if force {
+            for (name, note) in disabled {
+                eprintln!("Warning: {} is {}, installing it anyway", name, note);
+            }
+            return Ok(());
+        }
+        let list = disabled
+            .iter()
+            .map(|(name, note)| format!("  {name} ({note})"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        Err(CatError::Pac(format!(
+            "the following packages are disabled upstream:\n{list}\n\
+            Use `--force` to install them anyway"
+        )))
+    }
+}
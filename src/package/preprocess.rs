@@ -1,24 +1,61 @@
 use crate::{
-    PAC_PATH,
+    config::pac_root,
     errors::CatError,
+    interrupt,
     macos::file::add_permit,
     package::{
-        load_path::modify_load_path,
-        relocate::{relocate_install_prefix, replace_str},
+        load_path::{LoadCommandChange, LoadCommandKind, modify_load_path},
+        relocate::{Replacement, TextRelocator, relocate_install_prefix, unknown_placeholders},
     },
 };
-use apple_codesign::{CodeSignatureFlags, SigningSettings};
+use apple_codesign::{
+    AppleCodesignError, MachFile, MachOSigner, SettingsScope, SigningSettings,
+    UniversalBinaryBuilder, VerificationProblemType, verify_macho_data,
+};
 use goblin::mach::Mach;
+use rayon::prelude::*;
 use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
     fs::{self},
     io,
+    path::Path,
 };
 use walkdir::WalkDir;
 
-pub fn patch_binary(binary: Vec<u8>, prefix_with_version: &str) -> Result<Vec<u8>, CatError> {
-    let mut binary = modify_load_path(binary, prefix_with_version)?;
-    relocate_install_prefix(&mut binary, prefix_with_version, PAC_PATH)?;
-    Ok(binary)
+/// What is changed in a binary
+#[derive(Debug, Default)]
+pub struct PatchReport {
+    pub load_commands: Vec<LoadCommandChange>,
+    pub replacements: Vec<Replacement>,
+}
+
+impl PatchReport {
+    pub fn is_empty(&self) -> bool {
+        self.load_commands.is_empty() && self.replacements.is_empty()
+    }
+}
+
+impl fmt::Display for PatchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lines = self
+            .load_commands
+            .iter()
+            .map(|c| c.to_string())
+            .chain(self.replacements.iter().map(|r| r.to_string()))
+            .collect::<Vec<_>>();
+        write!(f, "  {}", lines.join("\n  "))
+    }
+}
+
+pub fn patch_binary(binary: Vec<u8>) -> Result<(Vec<u8>, PatchReport), CatError> {
+    let (mut binary, load_commands) = modify_load_path(binary, pac_root())?;
+    let replacements = relocate_install_prefix(&mut binary, pac_root())?;
+    let report = PatchReport {
+        load_commands,
+        replacements,
+    };
+    Ok((binary, report))
 }
 
 fn is_binary(data: &[u8]) -> bool {
@@ -43,66 +80,353 @@ fn is_executable_or_dylib(data: &[u8]) -> bool {
     }
 }
 
-pub fn before_install<P>(path: P, prefix_with_version: &str) -> Result<(), CatError>
+/// Files that must be left untouched even if they contain Homebrew paths,
+/// returns what kind of file it is
+fn blob_kind(data: &[u8]) -> Option<&'static str> {
+    use infer::MatcherType;
+    if let Some(kind) = infer::get(data) {
+        match kind.matcher_type() {
+            MatcherType::Text => {}
+            _ => return Some(kind.mime_type()),
+        }
+    }
+    // text in any single-byte encoding or UTF-8 never contains NUL,
+    // but compiled files such as `.mo` and `.pyc` do, and they store offsets
+    if memchr::memchr(0, data).is_some() {
+        return Some("binary data");
+    }
+    None
+}
+
+/// What happened to a single file, it is printed after the parallel walk
+/// so the output of a file stays together
+#[derive(Debug, Default)]
+struct FileOutcome {
+    log: Vec<String>,
+    warnings: Vec<PreprocessWarning>,
+    load_paths: Vec<String>,
+    unrelocatable: Option<String>,
+    invalid_signature: Option<String>,
+    unknown_tokens: BTreeSet<String>,
+}
+
+/// A problem that does not fail the install, it is reported with the package
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PreprocessWarning {
+    /// object files and data blobs keep their Homebrew paths
+    Skipped {
+        file: String,
+        kind: String,
+    },
+    PatchFailed {
+        file: String,
+        reason: String,
+    },
+    SignFailed {
+        file: String,
+        reason: String,
+    },
+    UnknownPlaceholder {
+        token: String,
+        files: Vec<String>,
+    },
+}
+
+impl fmt::Display for PreprocessWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Skipped { file, kind } => {
+                write!(f, "skip {file} ({kind}), Homebrew paths in it are kept")
+            }
+            Self::PatchFailed { file, reason } => {
+                write!(f, "can not patch binary {file}: {reason}")
+            }
+            Self::SignFailed { file, reason } => write!(
+                f,
+                "can not sign binary {file}: {reason}\n  \
+                You may need to sign it manually with `codesign --sign - --force <path>`"
+            ),
+            Self::UnknownPlaceholder { token, files } => write!(
+                f,
+                "unknown placeholder {token} is left in:\n  {}",
+                files.join("\n  ")
+            ),
+        }
+    }
+}
+
+/// What `before_install` did to a package
+#[derive(Debug, Default)]
+pub struct Preprocessed {
+    /// load paths of all patched binaries
+    pub load_paths: BTreeSet<String>,
+    pub warnings: Vec<PreprocessWarning>,
+}
+
+/// Ad hoc signing settings of a slice. The identifier, flags, runtime version and
+/// entitlements of the original signature are kept, the hardened runtime included
+fn signing_settings(
+    original: &[u8],
+    identifier: &str,
+) -> Result<SigningSettings<'static>, CatError> {
+    let mut settings = SigningSettings::default();
+    settings.import_settings_from_macho(original)?;
+    if settings.binary_identifier(SettingsScope::Main).is_none()
+        && settings
+            .binary_identifier(SettingsScope::MultiArchIndex(0))
+            .is_none()
+    {
+        settings.set_binary_identifier(SettingsScope::Main, identifier);
+    }
+    Ok(settings)
+}
+
+/// Re-sign a patched binary ad hoc, slice by slice, with the settings read from
+/// the same slice of the original binary
+pub fn sign_binary(original: &[u8], patched: &[u8], identifier: &str) -> Result<Vec<u8>, CatError> {
+    // patching never adds, removes or reorders slices
+    let originals = MachFile::parse(original)?.into_iter().map(|m| m.data);
+    let slices = MachFile::parse(patched)?.into_iter().map(|m| m.data);
+    let mut signed = originals
+        .zip(slices)
+        .collect::<Vec<_>>()
+        .into_par_iter()
+        .map(|(original, slice)| {
+            let settings = signing_settings(original, identifier)?;
+            let mut signed = Vec::new();
+            MachOSigner::new(slice)?.write_signed_binary(&settings, &mut signed)?;
+            Ok(signed)
+        })
+        .collect::<Result<Vec<_>, CatError>>()?;
+    if signed.len() == 1 {
+        return Ok(signed.remove(0));
+    }
+    let mut builder = UniversalBinaryBuilder::default();
+    for slice in signed.iter() {
+        builder
+            .add_binary(slice)
+            .map_err(AppleCodesignError::from)?;
+    }
+    let mut fat = Vec::new();
+    builder.write(&mut fat).map_err(AppleCodesignError::from)?;
+    Ok(fat)
+}
+
+/// Recompute the page and slot hashes of every signed slice, the kernel kills a
+/// binary whose hashes do not match. Ad hoc signatures have no CMS blob, and
+/// unsigned slices are already reported by the signing warning
+pub fn verify_signature(data: &[u8]) -> Result<(), CatError> {
+    let problems = verify_macho_data(data)
+        .into_iter()
+        .filter(|p| {
+            !matches!(
+                p.problem,
+                VerificationProblemType::NoMachOSignatureData
+                    | VerificationProblemType::NoCryptographicSignature
+                    | VerificationProblemType::CmsError(_)
+                    | VerificationProblemType::CmsOldDigestAlgorithm(_)
+                    | VerificationProblemType::CmsOldSignatureAlgorithm(_)
+            )
+        })
+        .map(|p| p.to_string())
+        .collect::<Vec<_>>();
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(CatError::Signature(problems.join("; ")))
+}
+
+fn preprocess_file(path: &Path, relocator: &TextRelocator) -> Result<FileOutcome, CatError> {
+    interrupt::check()?;
+    let mut outcome = FileOutcome::default();
+    let relative = path.strip_prefix(std::env::temp_dir()).unwrap().display();
+    let data = fs::read(path).inspect_err(|e| eprintln!("read {} data error: {e}", relative))?;
+    if is_binary(&data) {
+        if !is_executable_or_dylib(&data) {
+            if relocator.contains_placeholder(&data) {
+                outcome.warnings.push(PreprocessWarning::Skipped {
+                    file: relative.to_string(),
+                    kind: "object file".to_string(),
+                });
+            }
+            return Ok(outcome);
+        }
+        let (patched_binary, report) = match patch_binary(data.clone()) {
+            Ok(patched) => patched,
+            Err(CatError::Relocate(reason)) => {
+                outcome.unrelocatable = Some(format!("{relative}: {reason}"));
+                return Ok(outcome);
+            }
+            Err(e) => {
+                outcome.warnings.push(PreprocessWarning::PatchFailed {
+                    file: relative.to_string(),
+                    reason: e.to_string(),
+                });
+                return Ok(outcome);
+            }
+        };
+        // nothing changed, keep the file and its signature as they are
+        if report.is_empty() {
+            return Ok(outcome);
+        }
+        outcome
+            .log
+            .push(format!("patched binary: {}\n{}", relative, report));
+        outcome.load_paths = report
+            .load_commands
+            .into_iter()
+            .filter(|c| c.kind == LoadCommandKind::LoadDylib)
+            .filter_map(|c| c.new)
+            .collect();
+        let identifier = format!("org.pac.binary.{}", path.file_name().unwrap().display());
+        let binary = match sign_binary(&data, &patched_binary, &identifier) {
+            Ok(signed) => signed,
+            Err(e) => {
+                outcome.warnings.push(PreprocessWarning::SignFailed {
+                    file: relative.to_string(),
+                    reason: e.to_string(),
+                });
+                patched_binary
+            }
+        };
+        if let Err(e) = verify_signature(&binary) {
+            outcome.invalid_signature = Some(format!("{relative}: {e}"));
+            return Ok(outcome);
+        }
+        // add user write permission
+        add_permit(path, 0o200)?;
+        fs::write(path, binary)?;
+        return Ok(outcome);
+    }
+    // most files have nothing to relocate, a fast search avoids touching them
+    if !relocator.contains_placeholder(&data) {
+        return Ok(outcome);
+    }
+    if let Some(kind) = blob_kind(&data) {
+        outcome.warnings.push(PreprocessWarning::Skipped {
+            file: relative.to_string(),
+            kind: kind.to_string(),
+        });
+        return Ok(outcome);
+    }
+    let content = relocator.relocate(&data);
+    outcome.unknown_tokens = unknown_placeholders(&content);
+    if content != data {
+        add_permit(path, 0o200)?;
+        fs::write(path, content)?;
+    }
+    Ok(outcome)
+}
+
+/// Patch and sign binaries and relocate text files of a package before installing it.
+/// Binaries that can not be relocated into the prefix or fail the signature check
+/// fail the install with a list of them
+pub fn before_install<P>(path: P) -> Result<Preprocessed, CatError>
 where
-    P: AsRef<std::path::Path>,
+    P: AsRef<Path>,
 {
-    let walk = WalkDir::new(path);
-    for entry in walk {
+    let mut files = Vec::new();
+    for entry in WalkDir::new(path) {
         let entry = entry.map_err(|e| -> io::Error { e.into() })?;
-        if !entry.file_type().is_file() {
-            continue;
+        if entry.file_type().is_file() {
+            files.push(entry.into_path());
         }
-        let path = entry.path();
-        let relative = path.strip_prefix(std::env::temp_dir()).unwrap().display();
-        let data =
-            fs::read(path).inspect_err(|e| eprintln!("read {} data error: {e}", relative))?;
-        if is_binary(&data) {
-            if !is_executable_or_dylib(&data) {
-                continue;
-            }
-            println!("try to patch binary: {}", relative);
-            let patched_binary = match patch_binary(data, prefix_with_version) {
-                Ok(b) => b,
-                Err(e) => {
-                    eprintln!("Warning: can not patch binary: {} Error: {e}", relative);
-                    continue;
-                }
-            };
-            // add user write permission
-            add_permit(path, 0o200)?;
-            fs::write(path, patched_binary)?;
-            // sign the binary
-            let mut settings = SigningSettings::default();
-            settings.set_binary_identifier(
-                apple_codesign::SettingsScope::Main,
-                format!("org.pac.binary.{}", path.file_name().unwrap().display()),
-            );
-            settings.set_code_signature_flags(
-                apple_codesign::SettingsScope::Main,
-                CodeSignatureFlags::ADHOC | CodeSignatureFlags::ENFORCEMENT,
-            );
-            let sign = apple_codesign::UnifiedSigner::new(settings);
-            if let Err(e) = sign.sign_macho(&path, &path) {
-                eprintln!(
-                    "Warning: can not sign binary {}, error: {e}\n\
-                    You may need to sign it manually with `codesign --sign - --force <path>`",
-                    relative
-                );
-            }
-        } else {
-            // replace string here
-            let content = match String::from_utf8(data) {
-                Ok(s) => s,
-                Err(_) => {
-                    // if it is not a utf-8 file, skip
-                    continue;
-                }
-            };
-            let content = replace_str(&content, prefix_with_version, PAC_PATH);
-            add_permit(path, 0o200)?;
-            fs::write(&path, content)?;
+    }
+    let relocator = TextRelocator::new(pac_root());
+    let outcomes = files
+        .par_iter()
+        .map(|path| preprocess_file(path, &relocator))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut preprocessed = Preprocessed::default();
+    let mut unrelocatable = Vec::new();
+    let mut invalid_signatures = Vec::new();
+    let mut unknown_tokens = BTreeMap::<String, Vec<String>>::new();
+    for (path, outcome) in files.iter().zip(outcomes) {
+        for line in outcome.log {
+            println!("{line}");
+        }
+        preprocessed.warnings.extend(outcome.warnings);
+        preprocessed.load_paths.extend(outcome.load_paths);
+        unrelocatable.extend(outcome.unrelocatable);
+        invalid_signatures.extend(outcome.invalid_signature);
+        let relative = path.strip_prefix(std::env::temp_dir()).unwrap();
+        for token in outcome.unknown_tokens {
+            unknown_tokens
+                .entry(token)
+                .or_default()
+                .push(relative.display().to_string());
         }
     }
-    Ok(())
+    preprocessed.warnings.extend(
+        unknown_tokens
+            .into_iter()
+            .map(|(token, files)| PreprocessWarning::UnknownPlaceholder { token, files }),
+    );
+    if !unrelocatable.is_empty() {
+        return Err(CatError::Relocate(format!(
+            "the following binaries can not be relocated into {}:\n  {}",
+            pac_root(),
+            unrelocatable.join("\n  ")
+        )));
+    }
+    if !invalid_signatures.is_empty() {
+        return Err(CatError::Signature(format!(
+            "the following binaries would be killed on launch:\n  {}",
+            invalid_signatures.join("\n  ")
+        )));
+    }
+    Ok(preprocessed)
+}
+
+#[test]
+fn test_sign_binary() {
+    use crate::macos::fixture::{ARM64, Fixture, X86_64, fat};
+    use apple_codesign::CodeSignatureFlags;
+    let entitlements = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0"><dict><key>com.apple.security.cs.allow-jit</key><true/></dict></plist>"#;
+    let mut settings = SigningSettings::default();
+    settings.set_binary_identifier(SettingsScope::Main, "org.example.tool");
+    settings.set_code_signature_flags(SettingsScope::Main, CodeSignatureFlags::RUNTIME);
+    settings
+        .set_entitlements_xml(SettingsScope::Main, entitlements)
+        .unwrap();
+    let slices = [ARM64, X86_64].map(|cputype| {
+        let binary = Fixture::executable(cputype)
+            .section("__text", 512, b"\xc0\x03\x5f\xd6")
+            .linkedit()
+            .build();
+        let mut signed = Vec::new();
+        MachOSigner::new(&binary)
+            .unwrap()
+            .write_signed_binary(&settings, &mut signed)
+            .unwrap();
+        signed
+    });
+    let original = fat(&slices);
+
+    let signed = sign_binary(&original, &original, "org.pac.binary.tool").unwrap();
+    let slices = MachFile::parse(&signed)
+        .unwrap()
+        .into_iter()
+        .collect::<Vec<_>>();
+    assert_eq!(slices.len(), 2);
+    for slice in slices {
+        let signature = slice.code_signature().unwrap().unwrap();
+        let cd = signature.code_directory().unwrap().unwrap();
+        assert_eq!(cd.ident, "org.example.tool");
+        assert!(
+            cd.flags
+                .contains(CodeSignatureFlags::ADHOC | CodeSignatureFlags::RUNTIME)
+        );
+        let entitlements = signature.entitlements().unwrap().unwrap();
+        assert!(entitlements.as_str().contains("allow-jit"));
+    }
+    verify_signature(&signed).unwrap();
+    // a patch after signing, in the code of the first slice, which the
+    // re-signed fat binary aligns to 16 KiB
+    let mut tampered = signed.clone();
+    tampered[16384 + 512] ^= 1;
+    assert!(verify_signature(&tampered).is_err());
 }
@@ -1,83 +1,271 @@
-use std::io;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt, io,
+    ops::Range,
+};
 
-use crate::{BREW_CELLAR, BREW_CELLAR_ACTUAL, BREW_PREFIX};
+use aho_corasick::{AhoCorasick, MatchKind};
+use goblin::mach::{
+    Mach, MachO,
+    constants::{S_GB_ZEROFILL, S_THREAD_LOCAL_ZEROFILL, S_ZEROFILL, SECTION_TYPE},
+};
 
+use crate::{
+    BREW_CELLAR, BREW_CELLAR_ACTUAL, BREW_PREFIX, errors::CatError,
+    package::load_path::describe_arch,
+};
+
+const DEFAULT_PREFIX: &str = "/opt/homebrew";
+
+/// Sections that hold string literals, code and writable data are never touched
+const STRING_SECTIONS: [(&str, &str); 5] = [
+    // C strings
+    ("__TEXT", "__cstring"),
+    // constant char arrays, and string literals of Rust
+    ("__TEXT", "__const"),
+    // string literals of Go
+    ("__TEXT", "__rodata"),
+    // char arrays in constant structs that also hold pointers, they are
+    // moved out of `__TEXT` since the pointers are fixed up at load time
+    ("__DATA", "__const"),
+    ("__DATA_CONST", "__const"),
+];
+
+/// How many times a prefix is replaced in a section
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Replacement {
+    pub arch: &'static str,
+    /// such as `__TEXT,__cstring`
+    pub section: String,
+    pub pattern: &'static str,
+    pub count: usize,
+}
+
+impl fmt::Display for Replacement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{}] {}: {} x{}",
+            self.arch, self.section, self.pattern, self.count
+        )
+    }
+}
+
+/// Replace the prefixes in place, the replacement is padded with `/` so the length
+/// of the string is kept. Returns how many times each pattern is replaced
+fn replace_bytes(data: &mut [u8], ac: &AhoCorasick, new: &[&[u8]]) -> Vec<usize> {
+    let mut counts = vec![0; new.len()];
+    let matches = ac.find_iter(&*data).collect::<Vec<_>>();
+    for m in matches {
+        let index = m.pattern().as_usize();
+        let replacement = new[index];
+        let dst = &mut data[m.range()];
+        dst[..replacement.len()].copy_from_slice(replacement);
+        dst[replacement.len()..].fill(b'/');
+        counts[index] += 1;
+    }
+    counts
+}
+
+/// File ranges of the string sections of a single arch Mach-O
+fn string_sections(macho: &MachO, base: usize) -> Result<Vec<(String, Range<usize>)>, io::Error> {
+    let mut ranges = Vec::new();
+    for segment in macho.segments.iter() {
+        for (section, _) in segment.sections().map_err(io::Error::other)? {
+            let segname = section.segname().map_err(io::Error::other)?;
+            let sectname = section.name().map_err(io::Error::other)?;
+            if !STRING_SECTIONS.contains(&(segname, sectname)) {
+                continue;
+            }
+            let section_type = section.flags & SECTION_TYPE;
+            if section.offset == 0
+                || matches!(
+                    section_type,
+                    S_ZEROFILL | S_GB_ZEROFILL | S_THREAD_LOCAL_ZEROFILL
+                )
+            {
+                continue;
+            }
+            let start = base + section.offset as usize;
+            ranges.push((
+                format!("{segname},{sectname}"),
+                start..start + section.size as usize,
+            ));
+        }
+    }
+    Ok(ranges)
+}
+
+/// Replace Homebrew's prefix in the string sections of every slice with one
+/// Aho-Corasick pass per section. A prefix longer than the original one can not be
+/// put into embedded strings, such binaries are rejected without being modified
 pub fn relocate_install_prefix(
     binary: &mut [u8],
-    name_version: &str,
     new_prefix: &str,
-) -> Result<(), io::Error> {
-    let new_bytes = new_prefix.as_bytes();
-    const DEFAULT_BYTES: &[u8] = b"/opt/homebrew";
-    let prefix_1 = format!("{}/{}", BREW_CELLAR, name_version);
-    let prefix_2 = format!("{}/{}", BREW_CELLAR_ACTUAL, name_version);
-    let old_bytes_1 = prefix_1.as_bytes();
-    let old_bytes_2 = prefix_2.as_bytes();
-    if new_bytes.len() > old_bytes_1.len()
-        || new_bytes.len() > old_bytes_2.len()
-        || new_bytes.len() > DEFAULT_BYTES.len()
-    {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "New prefix is longer than old prefix or the default prefix",
-        ));
-    }
-    let mut parts = binary.split_mut(|&b| b == 0).collect::<Vec<_>>();
-    for s in parts.iter_mut() {
-        if s.windows(old_bytes_1.len()).any(|w| w == old_bytes_1) {
-            // println!("find bytes to replace new");
-            replace_bytes(s, old_bytes_1, new_bytes);
-            // println!("{:?}", String::from_utf8_lossy(s));
+) -> Result<Vec<Replacement>, CatError> {
+    let new_cellar = format!("{}/Cellar", new_prefix);
+    let pairs = [
+        (BREW_CELLAR, new_cellar.as_str()),
+        (BREW_CELLAR_ACTUAL, new_cellar.as_str()),
+        (DEFAULT_PREFIX, new_prefix),
+    ];
+    // leftmost longest, so cellar paths are not caught by the default prefix
+    let ac = AhoCorasick::builder()
+        .match_kind(MatchKind::LeftmostLongest)
+        .build(pairs.iter().map(|(old, _)| old))
+        .map_err(io::Error::other)?;
+    let new = pairs
+        .iter()
+        .map(|(_, new)| new.as_bytes())
+        .collect::<Vec<_>>();
+
+    let mut sections = Vec::new();
+    match Mach::parse(binary).map_err(io::Error::other)? {
+        Mach::Binary(macho) => {
+            let arch = describe_arch(macho.header.cputype);
+            for (name, range) in string_sections(&macho, 0)? {
+                sections.push((arch, name, range));
+            }
         }
-    }
-    for s in parts.iter_mut() {
-        if s.windows(old_bytes_2.len()).any(|w| w == old_bytes_2) {
-            // println!("find bytes to replace new");
-            replace_bytes(s, old_bytes_2, new_bytes);
-            // println!("{:?}", String::from_utf8_lossy(s));
+        Mach::Fat(fat) => {
+            for arch in fat.arches().map_err(io::Error::other)? {
+                let macho = MachO::parse(arch.slice(binary), 0).map_err(io::Error::other)?;
+                let name = describe_arch(arch.cputype);
+                for (section, range) in string_sections(&macho, arch.offset as usize)? {
+                    sections.push((name, section, range));
+                }
+            }
         }
     }
-    for s in parts.iter_mut() {
-        if s.windows(DEFAULT_BYTES.len()).any(|w| w == DEFAULT_BYTES) {
-            // println!("find bytes to replace default");
-            replace_bytes(s, DEFAULT_BYTES, new_bytes);
-            // println!("{:?}", String::from_utf8_lossy(s));
-        }
+
+    if let Some((_, section, _)) = sections.iter().find(|(_, _, r)| r.end > binary.len()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("section {section} is out of range"),
+        )
+        .into());
+    }
+    let too_long = sections
+        .iter()
+        .flat_map(|(arch, section, range)| {
+            ac.find_iter(&binary[range.clone()])
+                .filter(|m| new[m.pattern().as_usize()].len() > m.len())
+                .map(move |m| format!("[{arch}] {section}: {}", pairs[m.pattern().as_usize()].0))
+        })
+        .collect::<BTreeSet<_>>();
+    if !too_long.is_empty() {
+        return Err(CatError::Relocate(format!(
+            "{} is too long for the embedded paths: {}",
+            new_prefix,
+            too_long.into_iter().collect::<Vec<_>>().join(", ")
+        )));
     }
-    Ok(())
-}
 
-fn replace_bytes(data: &mut [u8], old: &[u8], new: &[u8]) {
-    let mut result = Vec::with_capacity(data.len());
-    let n = old.len() - new.len();
-    let mut i = 0;
-    while i < data.len() {
-        unsafe {
-            if data.get_unchecked(i..).starts_with(old) {
-                result.extend_from_slice(new);
-                result.extend(std::iter::repeat_n(47, n));
-                i += old.len();
-            } else {
-                result.push(*data.get_unchecked(i));
-                i += 1;
+    let mut counts = BTreeMap::new();
+    for (arch, section, range) in sections {
+        let data = &mut binary[range];
+        for (index, count) in replace_bytes(data, &ac, &new).into_iter().enumerate() {
+            if count > 0 {
+                *counts.entry((arch, section.clone(), index)).or_insert(0) += count;
             }
         }
     }
-    let n = data.len() - result.len();
-    result.extend(std::iter::repeat_n(0, n));
-    debug_assert!(result.len() == data.len());
-    unsafe {
-        std::ptr::copy_nonoverlapping(result.as_ptr(), data.as_mut_ptr(), data.len());
+    let replacements = counts
+        .into_iter()
+        .map(|((arch, section, index), count)| Replacement {
+            arch,
+            section,
+            pattern: pairs[index].0,
+            count,
+        })
+        .collect();
+    Ok(replacements)
+}
+
+const TOKEN_START: &[u8] = b"@@HOMEBREW_";
+
+/// What a placeholder becomes
+#[derive(Debug, Clone, Copy)]
+enum Replace {
+    /// a path under the install root
+    Root(&'static str),
+    /// a path outside of the install root
+    Fixed(&'static str),
+}
+
+/// Placeholders and Homebrew paths found in text files of bottles,
+/// add new ones here when Homebrew starts to use them
+const PLACEHOLDERS: [(&str, Replace); 8] = [
+    (BREW_CELLAR, Replace::Root("/Cellar")),
+    (BREW_PREFIX, Replace::Root("")),
+    // Homebrew keeps the repository in the prefix on Apple Silicon
+    ("@@HOMEBREW_REPOSITORY@@", Replace::Root("")),
+    ("@@HOMEBREW_LIBRARY@@", Replace::Root("/Library")),
+    (
+        "@@HOMEBREW_JAVA@@",
+        Replace::Root("/opt/openjdk/libexec/openjdk.jdk/Contents/Home"),
+    ),
+    ("@@HOMEBREW_PERL@@", Replace::Fixed("/usr/bin/perl")),
+    (BREW_CELLAR_ACTUAL, Replace::Root("/Cellar")),
+    (DEFAULT_PREFIX, Replace::Root("")),
+];
+
+/// Relocate text files such as `.pc`, `.la`, `.cmake`, scripts with shebangs
+/// and `*-config` scripts with one Aho-Corasick pass
+pub struct TextRelocator {
+    ac: AhoCorasick,
+    replacements: Vec<String>,
+}
+
+impl TextRelocator {
+    pub fn new(root: &str) -> Self {
+        let ac = AhoCorasick::builder()
+            .match_kind(MatchKind::LeftmostLongest)
+            .build(PLACEHOLDERS.iter().map(|(token, _)| token))
+            .expect("placeholders are valid patterns");
+        let replacements = PLACEHOLDERS
+            .iter()
+            .map(|(_, replace)| match replace {
+                Replace::Root(path) => format!("{root}{path}"),
+                Replace::Fixed(path) => path.to_string(),
+            })
+            .collect();
+        Self { ac, replacements }
+    }
+
+    /// Unknown `@@HOMEBREW_*@@` tokens count too, so they can be reported
+    pub fn contains_placeholder(&self, content: &[u8]) -> bool {
+        self.ac.is_match(content) || memchr::memmem::find(content, TOKEN_START).is_some()
+    }
+
+    /// Works on bytes, so text in any encoding is relocated as long as paths are ASCII
+    #[must_use = "this returns the replaced content as a new allocation, \
+                  without modifying the original"]
+    pub fn relocate(&self, content: &[u8]) -> Vec<u8> {
+        self.ac.replace_all_bytes(content, &self.replacements)
     }
 }
 
-#[must_use = "this returns the replaced string as a new allocation, \
-              without modifying the original"]
-pub fn replace_str(content: &str, name_version: &str, new_prefix: &str) -> String {
-    let prefix_1 = format!("{}/{}", BREW_CELLAR, name_version);
-    let prefix_2 = BREW_PREFIX;
-    let content = content.replace(&prefix_1, new_prefix);
-    content.replace(&prefix_2, new_prefix)
+/// `@@HOMEBREW_*@@` tokens that are not in the placeholder table
+pub fn unknown_placeholders(content: &[u8]) -> BTreeSet<String> {
+    let finder = memchr::memmem::Finder::new(TOKEN_START);
+    let mut tokens = BTreeSet::new();
+    let mut rest = content;
+    while let Some(start) = finder.find(rest) {
+        let after = &rest[start + TOKEN_START.len()..];
+        let len = after
+            .iter()
+            .take_while(|b| b.is_ascii_uppercase() || **b == b'_')
+            .count();
+        if len > 0 && after[len..].starts_with(b"@@") {
+            let end = start + TOKEN_START.len() + len + 2;
+            tokens.insert(String::from_utf8_lossy(&rest[start..end]).into_owned());
+            rest = &rest[end..];
+        } else {
+            rest = after;
+        }
+    }
+    tokens
 }
 
 #[test]
@@ -87,7 +275,105 @@ fn test_replace_bytes() {
     let mut data = "%ls/opt/homebrew/etc/opt/homebrew/Cellar/fish/4.1.2/bin/opt/homebrew/Cellar/fish/4.1.2/share/opt/homebrew/Cellar/fish/4.1.2/share/doc/fishbinshare/fish/private/tmp/fish-20251007-7845-i8mh0h/fish-4.1.2/buildUnexpected directory layout, using compiled-in pathsRunning out of build directory, using paths relative to $CARGO_MANIFEST_DIR (etcshareuser_doc/htmlRunning from relocatable treeshare/doc/fishInvalid executable path '', using compiled-in pathsc
     push/pop not allowed on global stack/opt/homebrew/Cellar/fish/4.1.2".to_string();
     let data_bytes = unsafe { data.as_bytes_mut() };
-    replace_bytes(data_bytes, old_prefix.as_bytes(), new_prefix.as_bytes());
+    let ac = AhoCorasick::new([old_prefix]).unwrap();
+    let counts = replace_bytes(data_bytes, &ac, &[new_prefix.as_bytes()]);
+    assert_eq!(counts, [4]);
     let result = "%ls/opt/homebrew/etc/opt/pac////////////////////////bin/opt/pac////////////////////////share/opt/pac////////////////////////share/doc/fishbinshare/fish/private/tmp/fish-20251007-7845-i8mh0h/fish-4.1.2/buildUnexpected directory layout, using compiled-in pathsRunning out of build directory, using paths relative to $CARGO_MANIFEST_DIR (etcshareuser_doc/htmlRunning from relocatable treeshare/doc/fishInvalid executable path '', using compiled-in pathsc\n    push/pop not allowed on global stack/opt/pac///////////////////////";
     assert_eq!(data, result);
 }
+
+#[test]
+fn test_relocate_install_prefix() {
+    use crate::macos::fixture::{ARM64, Fixture};
+    let text = b"\x01\x02/opt/homebrew\x03\x04";
+    let cstring =
+        b"/opt/homebrew/etc\0@@HOMEBREW_CELLAR@@/fish/4.1.2/share\0/opt/homebrew/Cellar/fish\0";
+    let mut binary = Fixture::executable(ARM64)
+        .section("__text", 512, text)
+        .section("__cstring", 1024, cstring)
+        .build();
+    let replacements = relocate_install_prefix(&mut binary, "/opt/pac").unwrap();
+    let report = replacements
+        .iter()
+        .map(|r| r.to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        report,
+        [
+            "[arm64] __TEXT,__cstring: @@HOMEBREW_CELLAR@@ x1",
+            "[arm64] __TEXT,__cstring: /opt/homebrew/Cellar x1",
+            "[arm64] __TEXT,__cstring: /opt/homebrew x1",
+        ]
+    );
+    // code is never touched
+    assert_eq!(&binary[512..512 + text.len()], text);
+    assert_eq!(
+        &binary[1024..1024 + cstring.len()],
+        b"/opt/pac//////etc\0/opt/pac/Cellar/////fish/4.1.2/share\0/opt/pac/Cellar//////fish\0"
+    );
+}
+
+#[test]
+fn test_relocate_long_prefix() {
+    use crate::macos::fixture::{ARM64, Fixture};
+    let long_prefix = "/Users/someone/.local/pac";
+    // only code mentions the prefix, nothing to relocate
+    let text = b"\x01\x02/opt/homebrew\x03\x04";
+    let mut binary = Fixture::executable(ARM64)
+        .section("__text", 512, text)
+        .section("__cstring", 1024, b"hello\0")
+        .build();
+    assert!(
+        relocate_install_prefix(&mut binary, long_prefix)
+            .unwrap()
+            .is_empty()
+    );
+    let cstring = b"/opt/homebrew/etc\0";
+    let mut binary = Fixture::executable(ARM64)
+        .section("__text", 512, b"")
+        .section("__cstring", 1024, cstring)
+        .build();
+    let original = binary.clone();
+    let Err(CatError::Relocate(reason)) = relocate_install_prefix(&mut binary, long_prefix) else {
+        panic!("expected a relocate error");
+    };
+    assert!(reason.contains("[arm64] __TEXT,__cstring: /opt/homebrew"));
+    assert_eq!(binary, original);
+}
+
+#[test]
+fn test_text_relocator() {
+    let relocator = TextRelocator::new("/opt/pac");
+    let content = "#!@@HOMEBREW_PREFIX@@/opt/python@3.13/bin/python3.13\n\
+        prefix=@@HOMEBREW_CELLAR@@/glib/2.86.0\n\
+        cellar=@@HOMEBREW_CELLAR@@\n\
+        repo=@@HOMEBREW_REPOSITORY@@ lib=@@HOMEBREW_LIBRARY@@/Homebrew\n\
+        #!@@HOMEBREW_PERL@@\n\
+        JAVA_HOME=@@HOMEBREW_JAVA@@\n\
+        libdir='/opt/homebrew/Cellar/gettext/0.26/lib' share=/opt/homebrew/share\n\
+        @@HOMEBREW_UNKNOWN@@ @@HOMEBREW_ @@HOMEBREW_PREFIX";
+    let relocated = relocator.relocate(content.as_bytes());
+    assert_eq!(
+        String::from_utf8(relocated.clone()).unwrap(),
+        "#!/opt/pac/opt/python@3.13/bin/python3.13\n\
+        prefix=/opt/pac/Cellar/glib/2.86.0\n\
+        cellar=/opt/pac/Cellar\n\
+        repo=/opt/pac lib=/opt/pac/Library/Homebrew\n\
+        #!/usr/bin/perl\n\
+        JAVA_HOME=/opt/pac/opt/openjdk/libexec/openjdk.jdk/Contents/Home\n\
+        libdir='/opt/pac/Cellar/gettext/0.26/lib' share=/opt/pac/share\n\
+        @@HOMEBREW_UNKNOWN@@ @@HOMEBREW_ @@HOMEBREW_PREFIX"
+    );
+    assert_eq!(
+        unknown_placeholders(&relocated)
+            .into_iter()
+            .collect::<Vec<_>>(),
+        ["@@HOMEBREW_UNKNOWN@@"]
+    );
+    // Latin-1 text is relocated byte by byte
+    let latin1 = b"Fran\xe7ois: @@HOMEBREW_PREFIX@@/share/man\xa0";
+    assert_eq!(
+        relocator.relocate(latin1),
+        b"Fran\xe7ois: /opt/pac/share/man\xa0"
+    );
+}
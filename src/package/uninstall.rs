@@ -1,15 +1,61 @@
-use std::path::Path;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
 
 use walkdir::WalkDir;
 
 use crate::{
-    PAC_PATH,
+    config::pac_root,
     database::local::{PacState, SqlTransaction},
     errors::CatError,
+    interrupt,
     macos::file::{remove_dir_force, remove_file_force},
+    package::journal::{self, Action, Entry, Journal},
 };
 
-pub async fn uninstall_a_pac(name: &str) -> Result<(), CatError> {
+/// Remove the links in the prefix and the files in the keg,
+/// files that are already removed are ignored
+pub(crate) fn remove_installed<'a, I>(name: &str, paths: I) -> Result<(), CatError>
+where
+    I: Iterator<Item = &'a PathBuf>,
+{
+    for file in paths {
+        if let Err(e) = remove_file_force(file)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            eprintln!("Failed to remove file {:?}: {}", file, e);
+            return Err(CatError::Pac(format!("Cannot uninstall package {}", name)));
+        }
+    }
+    Ok(())
+}
+
+/// List the binaries of other packages that load the dylibs of a package,
+/// they would fail to launch once the package is removed
+async fn find_broken_linkage(id: i64, tx: &mut SqlTransaction) -> Result<Option<String>, CatError> {
+    let linked_by = tx.get_linked_by(id).await?;
+    if linked_by.is_empty() {
+        return Ok(None);
+    }
+    let list = linked_by
+        .iter()
+        .map(|(name, binary, dylib)| {
+            format!(
+                "  {} ({}) loads {}",
+                binary.display(),
+                name,
+                dylib.display()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    Ok(Some(list))
+}
+
+/// Uninstall a package and the orphans it leaves, `force` removes packages
+/// whose dylibs are still loaded by other packages
+pub async fn uninstall_a_pac(name: &str, force: bool) -> Result<(), CatError> {
     // find the pac id
     let mut tx = SqlTransaction::new().await?;
     let (id, state) = match tx.is_installed(name).await? {
@@ -19,6 +65,24 @@ pub async fn uninstall_a_pac(name: &str) -> Result<(), CatError> {
             return Ok(());
         }
     };
+    // check before marking it broken, so a refused uninstall keeps it intact
+    if let Some(list) = find_broken_linkage(id, &mut tx).await? {
+        if !force {
+            return Err(CatError::Pac(format!(
+                "Cannot uninstall package {} because these binaries load its libraries:\n{}\n\
+                Use `--force` to uninstall it anyway",
+                name, list
+            )));
+        }
+        eprintln!(
+            "Warning: these binaries will break after uninstalling {}:\n{}",
+            name, list
+        );
+    }
+    // nothing is changed yet, stop here on Ctrl-C
+    interrupt::check()?;
+    // a package marked broken but not removed yet is finished by `pac repair`
+    let _journal = Journal::begin(Action::Uninstall)?;
     if let PacState::Installed = state {
         tx.update_pac_state(id, PacState::Broken).await?;
         tx.commit().await?;
@@ -32,53 +96,64 @@ pub async fn uninstall_a_pac(name: &str) -> Result<(), CatError> {
             name, rev_deps
         )));
     }
-    // find installed files
+    journal::record(&Entry::Remove {
+        name: name.to_string(),
+    })?;
+    // find installed files and links, then remove them
     let installed_files = tx.get_installed_files(id).await?;
-    // remove installed files
-    for file in installed_files.iter() {
-        if let Err(e) = remove_file_force(&file) {
-            if e.kind() != std::io::ErrorKind::NotFound {
-                eprintln!("Failed to remove file {:?}: {}", file, e);
-                return Err(CatError::Pac(format!("Cannot uninstall package {}", name)));
-            }
-        }
-    }
+    let links = tx.get_links(id).await?;
+    remove_installed(
+        name,
+        links.iter().map(|(p, _)| p).chain(installed_files.iter()),
+    )?;
     // remove pac record from database
     tx.delete_a_pac(id).await?;
     tx.commit().await?;
     println!("Pac {} is removed", name);
     let mut tx = SqlTransaction::new().await?;
-    // check orphan deps
+    // check orphan deps, but keep the ones other binaries still load
+    let mut kept = HashSet::new();
     let mut orphan_pacs = tx.get_orphan_pacs().await?;
     while !orphan_pacs.is_empty() {
         for (id, name, state) in orphan_pacs {
-            println!("removing orphan pac: {}", name);
+            interrupt::check()?;
             tx = SqlTransaction::new().await?;
+            if let Some(list) = find_broken_linkage(id, &mut tx).await? {
+                if !force {
+                    println!("keeping orphan pac {}, it is loaded by:\n{}", name, list);
+                    kept.insert(id);
+                    continue;
+                }
+                eprintln!(
+                    "Warning: these binaries will break after removing orphan pac {}:\n{}",
+                    name, list
+                );
+            }
+            println!("removing orphan pac: {}", name);
             let installed_files = tx.get_installed_files(id).await?;
+            let links = tx.get_links(id).await?;
             if let PacState::Installed = state {
                 tx.update_pac_state(id, PacState::Broken).await?;
                 tx.commit().await?;
                 tx = SqlTransaction::new().await?;
             }
+            journal::record(&Entry::Remove { name: name.clone() })?;
             // remove installed files
-            for file in installed_files {
-                if let Err(e) = remove_file_force(&file) {
-                    if e.kind() != std::io::ErrorKind::NotFound {
-                        eprintln!("Failed to remove file {:?}: {}", file, e);
-                        return Err(CatError::Pac(format!("Cannot uninstall package {}", name)));
-                    }
-                }
-            }
+            remove_installed(
+                &name,
+                links.iter().map(|(p, _)| p).chain(installed_files.iter()),
+            )?;
             tx.delete_a_pac(id).await?;
             tx.commit().await?;
             println!("Pac {} is removed", name);
         }
         tx = SqlTransaction::new().await?;
         orphan_pacs = tx.get_orphan_pacs().await?;
+        orphan_pacs.retain(|(id, _, _)| !kept.contains(id));
     }
 
     // clean empty dirs
-    let mut walk = WalkDir::new(PAC_PATH)
+    let mut walk = WalkDir::new(pac_root())
         .contents_first(true)
         .into_iter()
         .filter_entry(|e| e.file_type().is_dir())
@@ -1,18 +1,40 @@
-pub const INIT_DB: &str = include_str!("init_database.sql");
+/// Schema migrations, the one at index `i` brings `user_version` to `i + 1`.
+/// Append new ones, never edit a released one
+pub const MIGRATIONS: [&str; 6] = [
+    include_str!("migrations/0001_baseline.sql"),
+    include_str!("migrations/0002_caveats.sql"),
+    include_str!("migrations/0003_bottle_tag.sql"),
+    include_str!("migrations/0004_links.sql"),
+    include_str!("migrations/0005_keg_only.sql"),
+    include_str!("migrations/0006_linkage.sql"),
+];
 
 pub const INSERT_PAC: &str = include_str!("insert_pac.sql");
 pub const INSERT_DEP: &str = include_str!("insert_dep.sql");
 pub const INSERT_CONFLICT: &str = include_str!("insert_conflict.sql");
 pub const INSERT_INSTALLED_FILE: &str = include_str!("insert_installed_file.sql");
+pub const INSERT_LINK: &str = include_str!("insert_link.sql");
+pub const INSERT_LINKAGE: &str = include_str!("insert_linkage.sql");
 
 pub const SELECT_PAC_NAME: &str = include_str!("select_pac_name.sql");
 pub const SELECT_PAC_NAMES: &str = include_str!("select_pac_names.sql");
+pub const SELECT_PAC_INFO: &str = include_str!("select_pac_info.sql");
+pub const SELECT_PAC_VERSIONS: &str = include_str!("select_pac_versions.sql");
 pub const SELECT_PAC_ID: &str = include_str!("select_pac_id.sql");
 pub const SELECT_EXIST_FILE: &str = include_str!("select_exist_file.sql");
 pub const SELECT_INSTALLED_FILE: &str = include_str!("select_installed_file.sql");
+pub const SELECT_LINKS: &str = include_str!("select_links.sql");
+pub const SELECT_LINK_TARGET: &str = include_str!("select_link_target.sql");
+pub const SELECT_PATH_OWNER: &str = include_str!("select_path_owner.sql");
+pub const SELECT_DEPS: &str = include_str!("select_deps.sql");
+pub const SELECT_LINKED_BY: &str = include_str!("select_linked_by.sql");
+pub const SELECT_HAS_TABLE: &str = include_str!("select_has_table.sql");
 pub const SELECT_REVERSE_DEP: &str = include_str!("select_reverse_dep.sql");
 pub const SELECT_ORPHAN_PAC: &str = include_str!("select_orphan_pac.sql");
 
 pub const DELETE_PAC: &str = include_str!("delete_pac.sql");
+pub const DELETE_LINK: &str = include_str!("delete_link.sql");
 
 pub const UPDATE_PAC_STATE: &str = include_str!("update_pac_state.sql");
+pub const RENAME_PAC: &str = include_str!("rename_pac.sql");
+pub const RENAME_DEP: &str = include_str!("rename_dep.sql");
@@ -0,0 +1,2 @@
+DELETE FROM links
+WHERE LOWER(path) = LOWER($1);
@@ -0,0 +1,2 @@
+INSERT INTO links (installed_id, path, target)
+VALUES ($1, $2, $3)
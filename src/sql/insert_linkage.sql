@@ -0,0 +1,2 @@
+INSERT INTO linkage (installed_id, binary, dylib)
+VALUES ($1, $2, $3)
@@ -1,2 +1,2 @@
-INSERT INTO installed_packages (name, version, build_epoch, arch, channel, install_root, explicit, install_time, update_time, checksum)
-VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $8, $9)
+INSERT INTO installed_packages (name, version, build_epoch, arch, channel, install_root, explicit, install_time, update_time, checksum, caveats, bottle_tag, keg_only)
+VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $8, $9, $10, $11, $12)
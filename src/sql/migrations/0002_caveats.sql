@@ -0,0 +1 @@
+ALTER TABLE installed_packages ADD COLUMN caveats TEXT;  -- caveats shown after installation
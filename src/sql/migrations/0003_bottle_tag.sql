@@ -0,0 +1 @@
+ALTER TABLE installed_packages ADD COLUMN bottle_tag TEXT;  -- bottle tag, such as arm64_sonoma/all
@@ -0,0 +1,9 @@
+CREATE TABLE links (
+  id             INTEGER PRIMARY KEY,
+  installed_id   INTEGER NOT NULL,    -- installed_packages.id
+  path           TEXT    NOT NULL,    -- absolute path of the symlink in the prefix
+  target         TEXT    NOT NULL,    -- absolute path the symlink points to
+  FOREIGN KEY(installed_id) REFERENCES installed_packages(id) ON DELETE CASCADE
+);
+CREATE UNIQUE INDEX idx_links_unique_path ON links(LOWER(path));
+CREATE INDEX idx_links_by_pkg ON links(installed_id);
@@ -0,0 +1,2 @@
+-- 1=not linked into the prefix by default
+ALTER TABLE installed_packages ADD COLUMN keg_only INTEGER NOT NULL DEFAULT 0;
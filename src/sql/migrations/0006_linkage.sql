@@ -0,0 +1,9 @@
+CREATE TABLE linkage (
+  id             INTEGER PRIMARY KEY,
+  installed_id   INTEGER NOT NULL,    -- installed_packages.id of the package owning the binary
+  binary         TEXT    NOT NULL,    -- absolute path of the Mach-O file
+  dylib          TEXT    NOT NULL,    -- absolute path of the dylib it loads, owned by another package
+  FOREIGN KEY(installed_id) REFERENCES installed_packages(id) ON DELETE CASCADE
+);
+CREATE INDEX idx_linkage_dylib ON linkage(LOWER(dylib));
+CREATE INDEX idx_linkage_by_pkg ON linkage(installed_id);
@@ -0,0 +1,8 @@
+UPDATE dependencies
+SET dep_name = $2
+WHERE dep_name = $1
+  AND dependent_id IN (
+    SELECT id
+    FROM installed_packages
+    WHERE install_root = $3
+  );
@@ -0,0 +1,4 @@
+UPDATE installed_packages
+SET name = $2
+WHERE name = $1
+  AND install_root = $3;
@@ -0,0 +1,3 @@
+SELECT dep_name
+FROM dependencies
+WHERE dependent_id = $1;
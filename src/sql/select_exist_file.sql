@@ -1,3 +1,5 @@
 SELECT EXISTS(
     SELECT 1 FROM installed_files WHERE LOWER(path) = LOWER($1)
+    UNION ALL
+    SELECT 1 FROM links WHERE LOWER(path) = LOWER($1)
 );
@@ -0,0 +1 @@
+SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = $1;
@@ -0,0 +1,4 @@
+SELECT target
+FROM links
+WHERE LOWER(path) = LOWER($1)
+LIMIT 1;
@@ -0,0 +1,9 @@
+SELECT installed_packages.name, linkage.binary, linkage.dylib
+FROM linkage
+JOIN installed_files
+    ON LOWER(installed_files.path) = LOWER(linkage.dylib)
+JOIN installed_packages
+    ON installed_packages.id = linkage.installed_id
+WHERE installed_files.installed_id = $1
+  AND linkage.installed_id != $1
+ORDER BY installed_packages.name, linkage.binary;
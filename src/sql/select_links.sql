@@ -0,0 +1,3 @@
+SELECT links.path, links.target
+FROM links
+WHERE installed_id = $1
@@ -1,5 +1,12 @@
 SELECT a.id, a.name, a.state
 FROM installed_packages AS a
-LEFT JOIN dependencies AS b
-    ON a.name = b.dep_name
-WHERE a.explicit = 0 AND b.dep_name IS NULL;
+WHERE a.install_root = $1
+  AND a.explicit = 0
+  AND NOT EXISTS (
+    SELECT 1
+    FROM dependencies AS b
+    JOIN installed_packages AS c
+        ON c.id = b.dependent_id
+    WHERE b.dep_name = a.name
+      AND c.install_root = a.install_root
+  );
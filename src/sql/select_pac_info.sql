@@ -0,0 +1,5 @@
+SELECT name, version, arch, bottle_tag, explicit, keg_only, install_time, state, caveats
+FROM installed_packages
+WHERE name = $1
+  AND install_root = $2
+LIMIT 1;
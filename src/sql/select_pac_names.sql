@@ -1,3 +1,4 @@
 SELECT name
 FROM installed_packages
+WHERE install_root = $1
 ORDER BY name COLLATE NOCASE ASC;
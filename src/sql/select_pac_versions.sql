@@ -0,0 +1,4 @@
+SELECT name, version
+FROM installed_packages
+WHERE install_root = $1
+ORDER BY name COLLATE NOCASE ASC;
@@ -0,0 +1,6 @@
+SELECT installed_packages.name
+FROM installed_files
+JOIN installed_packages
+    ON installed_packages.id = installed_files.installed_id
+WHERE LOWER(installed_files.path) = LOWER($1)
+LIMIT 1;
@@ -1,3 +1,7 @@
-SELECT dependent_id
+SELECT dependencies.dependent_id
 FROM dependencies
-WHERE dep_name = $1 AND dep_type = 'runtime';
+JOIN installed_packages
+    ON installed_packages.id = dependencies.dependent_id
+WHERE dependencies.dep_name = $1
+  AND dependencies.dep_type = 'runtime'
+  AND installed_packages.install_root = $2;